@@ -0,0 +1,436 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use comfy_table::Table;
+
+use crate::benchmark::{perform_benchmark, BenchmarkConfig};
+use crate::manifest::Manifest;
+use crate::results::Summary;
+use crate::server_monitor::samples_path_for;
+
+/// Upper bound of the search if neither the concurrency nor the file
+/// descriptor limit give a lower one.
+const MAX_TUNED_CONCURRENCY: usize = 4096;
+/// File descriptors left to the rest of the client when the search is bounded
+/// by the file descriptor limit.
+const RESERVED_FDS: u64 = 64;
+/// The search stops once the highest passing and the lowest failing
+/// concurrency are this close, relative to the passing one.
+const PRECISION: f64 = 0.1;
+
+/// Latency and error objective a load level has to meet.
+///
+/// Parsed from `p99<5ms`, optionally followed by the share of commands that
+/// may fail, e.g. `p99.9<10ms,errors<0.1%`. No errors are allowed by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slo {
+    /// percentile of the command latencies in `[0, 1]`
+    pub percentile: f64,
+    pub latency: Duration,
+    /// share of commands that may fail or mispredict, in `[0, 1]`
+    pub error_rate: f64,
+}
+
+impl FromStr for Slo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut latency = None;
+        let mut error_rate = 0.0;
+        for part in s.split(',') {
+            let (key, bound) = part
+                .split_once('<')
+                .ok_or_else(|| format!("expected a bound like p99<5ms, found {:?}", part))?;
+            let (key, bound) = (key.trim(), bound.trim());
+            if key == "errors" {
+                error_rate = match bound.strip_suffix('%') {
+                    Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+                    None => bound.parse::<f64>(),
+                }
+                .ok()
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .ok_or_else(|| format!("invalid error rate {:?}", bound))?;
+            } else if let Some(percentile) = key.strip_prefix('p') {
+                let percentile = percentile
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|p| *p > 0.0 && *p <= 100.0)
+                    .ok_or_else(|| format!("invalid percentile {:?}", key))?;
+                let bound = parse_duration::parse(bound)
+                    .map_err(|e| format!("invalid latency {:?} => {}", bound, e))?;
+                latency = Some((percentile / 100.0, bound));
+            } else {
+                return Err(format!(
+                    "unknown objective {:?}, expected a percentile like p99 or errors",
+                    key
+                ));
+            }
+        }
+        let (percentile, latency) =
+            latency.ok_or_else(|| format!("missing latency objective in {:?}", s))?;
+        Ok(Self {
+            percentile,
+            latency,
+            error_rate,
+        })
+    }
+}
+
+impl Display for Slo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p{}<{:?},errors<{}%",
+            self.percentile * 100.0,
+            self.latency,
+            self.error_rate * 100.0
+        )
+    }
+}
+
+/// Outcome of running the benchmark at one concurrency.
+#[derive(Debug, Clone)]
+pub struct TuneStep {
+    pub concurrency: usize,
+    /// patterns completed per second
+    pub throughput: f64,
+    /// latency at the percentile of the objective
+    pub latency: Option<Duration>,
+    pub error_rate: f64,
+    pub passed: bool,
+}
+
+impl TuneStep {
+    /// A step whose benchmark failed, it counts as every command failing.
+    fn failed(concurrency: usize) -> Self {
+        Self {
+            concurrency,
+            throughput: 0.0,
+            latency: None,
+            error_rate: 1.0,
+            passed: false,
+        }
+    }
+
+    fn new(concurrency: usize, summary: &mut Summary, duration: Duration, slo: &Slo) -> Self {
+        let latency = summary.percentile(slo.percentile);
+        let error_rate = match summary.commands() {
+            0 => 1.0,
+            commands => summary.errors() as f64 / commands as f64,
+        };
+        Self {
+            concurrency,
+            throughput: summary.patterns() as f64 / duration.as_secs_f64(),
+            latency,
+            error_rate,
+            passed: latency.is_some_and(|l| l <= slo.latency) && error_rate <= slo.error_rate,
+        }
+    }
+}
+
+/// Steps of an auto-tune run, in the order they were measured.
+#[derive(Debug)]
+pub struct AutoTuneReport {
+    pub slo: Slo,
+    pub steps: Vec<TuneStep>,
+}
+
+impl AutoTuneReport {
+    /// Highest concurrency that met the objective.
+    pub fn best(&self) -> Option<&TuneStep> {
+        self.steps
+            .iter()
+            .filter(|s| s.passed)
+            .max_by_key(|s| s.concurrency)
+    }
+
+    /// Moves the results, manifest and server samples of the best step, or of
+    /// the first one if no step met the objective, to `out_file`, deletes
+    /// those of the other steps and writes a summary of every step next to
+    /// it.
+    fn persist(&self, out_file: &Path) -> std::io::Result<()> {
+        let kept = self.best().or(self.steps.first()).map(|s| s.concurrency);
+        for step in self.steps.iter() {
+            let results = step_path(out_file, step.concurrency);
+            let files = [
+                (Manifest::path_for(&results), Manifest::path_for(out_file)),
+                (samples_path_for(&results), samples_path_for(out_file)),
+                (results, out_file.to_path_buf()),
+            ];
+            for (from, to) in files {
+                // server samples are only written when a server is monitored
+                if !from.exists() {
+                    continue;
+                }
+                if Some(step.concurrency) == kept {
+                    std::fs::rename(from, to)?;
+                } else {
+                    std::fs::remove_file(from)?;
+                }
+            }
+        }
+        if let Some(kept) = kept {
+            println!(
+                "auto-tune: wrote the results of concurrency {} to {:?}",
+                kept, out_file
+            );
+        }
+
+        let steps_path = out_file.with_extension("steps.csv");
+        let mut steps = std::io::BufWriter::new(std::fs::File::create(&steps_path)?);
+        writeln!(
+            steps,
+            "concurrency,patterns_per_second,latency,error_rate,passed"
+        )?;
+        for step in self.steps.iter() {
+            writeln!(
+                steps,
+                "{},{},{},{},{}",
+                step.concurrency,
+                step.throughput,
+                step.latency
+                    .map_or("-".to_string(), |l| l.as_nanos().to_string()),
+                step.error_rate,
+                step.passed
+            )?;
+        }
+        steps.flush()?;
+        println!("auto-tune: wrote the step summary to {:?}", steps_path);
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        let mut steps: Vec<&TuneStep> = self.steps.iter().collect();
+        steps.sort_by_key(|s| s.concurrency);
+        let mut table = Table::new();
+        table.set_header(vec![
+            "concurrency",
+            "patterns/s",
+            &format!("p{}", self.slo.percentile * 100.0),
+            "error rate",
+            "slo",
+        ]);
+        for step in steps {
+            table.add_row(vec![
+                step.concurrency.to_string(),
+                format!("{:.0}", step.throughput),
+                step.latency.map_or("-".to_string(), |l| format!("{:?}", l)),
+                format!("{:.3}%", step.error_rate * 100.0),
+                if step.passed { "met" } else { "missed" }.to_string(),
+            ]);
+        }
+        println!("{table}");
+
+        match self.best() {
+            Some(best) => println!(
+                "highest load meeting {}: concurrency {} at {:.0} patterns/s, p{} {:?}",
+                self.slo,
+                best.concurrency,
+                best.throughput,
+                self.slo.percentile * 100.0,
+                best.latency.unwrap_or_default()
+            ),
+            None => println!("no load level met {}", self.slo),
+        }
+    }
+}
+
+/// Results file of the step at `concurrency`, `result.csv` gets
+/// `result.concurrency-8.csv`.
+fn step_path(out_file: &Path, concurrency: usize) -> PathBuf {
+    out_file.with_extension(format!("concurrency-{}.csv", concurrency))
+}
+
+/// Doubles the concurrency until the objective is missed, then bisects
+/// between the highest passing and the lowest failing concurrency.
+async fn search<F, Fut>(
+    max_concurrency: usize,
+    mut measure: F,
+) -> Result<Vec<TuneStep>, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: FnMut(usize) -> Fut,
+    Fut: Future<Output = Result<TuneStep, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let mut steps = Vec::new();
+    let mut passed = 0;
+    let mut failed = None;
+    let mut concurrency = 1;
+    loop {
+        let step = measure(concurrency).await?;
+        let step_passed = step.passed;
+        steps.push(step);
+        if !step_passed {
+            failed = Some(concurrency);
+            break;
+        }
+        passed = concurrency;
+        if concurrency >= max_concurrency {
+            break;
+        }
+        concurrency = (concurrency * 2).min(max_concurrency);
+    }
+
+    while let Some(failing) = failed {
+        let tolerance = ((passed as f64 * PRECISION) as usize).max(1);
+        if passed == 0 || failing - passed <= tolerance {
+            break;
+        }
+        let concurrency = passed + (failing - passed) / 2;
+        let step = measure(concurrency).await?;
+        if step.passed {
+            passed = concurrency;
+        } else {
+            failed = Some(concurrency);
+        }
+        steps.push(step);
+    }
+
+    Ok(steps)
+}
+
+/// Searches for the highest concurrency at which the benchmark still meets
+/// `slo`, running the configured benchmark once per step.
+pub(crate) async fn auto_tune(
+    mut config: BenchmarkConfig,
+    slo: Slo,
+) -> Result<AutoTuneReport, Box<dyn std::error::Error + Send + Sync>> {
+    let max_concurrency = config.concurrency.unwrap_or_else(|| {
+        config
+            .fd_limit
+            .map_or(MAX_TUNED_CONCURRENCY as u64, |limit| {
+                limit.saturating_sub(RESERVED_FDS)
+            })
+            .clamp(1, MAX_TUNED_CONCURRENCY as u64) as usize
+    });
+    // every step replays the data file from its start, fresh keys keep the
+    // predictions of one step from being spoiled by the ones before
+    config.salt = true;
+    let out_file = config.out_file.take();
+    let mut generate = config.generate.take();
+
+    let duration = config.duration;
+    let steps = search(max_concurrency, |concurrency| {
+        let mut step_config = config.clone();
+        step_config.concurrency = Some(concurrency);
+        step_config.generate = generate.take();
+        step_config.out_file = out_file.as_deref().map(|o| step_path(o, concurrency));
+        async move {
+            println!("auto-tune: running with concurrency {}", concurrency);
+            let report = match perform_benchmark(step_config).await {
+                Ok(report) => report,
+                // a load level the client or the server can't run at all
+                // misses the objective, so the search continues below it
+                Err(e) => {
+                    println!("auto-tune: concurrency {} failed => {}", concurrency, e);
+                    return Ok(TuneStep::failed(concurrency));
+                }
+            };
+            let mut summary = Summary::default();
+            for response in report.responses.iter() {
+                summary.add(&response.timing);
+            }
+            let step = TuneStep::new(concurrency, &mut summary, duration, &slo);
+            println!(
+                "auto-tune: concurrency {} {} the objective at {:.0} patterns/s",
+                concurrency,
+                if step.passed { "met" } else { "missed" },
+                step.throughput
+            );
+            Ok(step)
+        }
+    })
+    .await?;
+
+    let report = AutoTuneReport { slo, steps };
+    if let Some(out_file) = out_file {
+        report.persist(&out_file)?;
+    }
+    Ok(report)
+}
+
+#[test]
+fn test_parse_slo() {
+    let slo: Slo = "p99.9<10ms,errors<0.5%".parse().unwrap();
+    assert!((slo.percentile - 0.999).abs() < 1e-9);
+    assert_eq!(slo.latency, Duration::from_millis(10));
+    assert!((slo.error_rate - 0.005).abs() < 1e-9);
+    assert_eq!("p99<5ms".parse::<Slo>().unwrap().error_rate, 0.0);
+    assert!("errors<1%".parse::<Slo>().is_err());
+    assert!("p101<5ms".parse::<Slo>().is_err());
+}
+
+#[tokio::test]
+async fn test_search_finds_highest_passing_concurrency() {
+    let capacity = 37;
+    for overload in [false, true] {
+        let steps = search(1000, |concurrency| async move {
+            if overload && concurrency > capacity {
+                return Ok(TuneStep::failed(concurrency));
+            }
+            Ok(TuneStep {
+                concurrency,
+                throughput: concurrency as f64,
+                latency: Some(Duration::from_millis(1)),
+                error_rate: 0.0,
+                passed: concurrency <= capacity,
+            })
+        })
+        .await
+        .unwrap();
+        let report = AutoTuneReport {
+            slo: "p99<5ms".parse().unwrap(),
+            steps,
+        };
+        let best = report.best().unwrap().concurrency;
+        assert!(best <= capacity && best as f64 >= capacity as f64 * (1.0 - PRECISION));
+    }
+
+    let steps = search(8, |concurrency| async move {
+        Ok(TuneStep {
+            concurrency,
+            throughput: 0.0,
+            latency: None,
+            error_rate: 0.0,
+            passed: true,
+        })
+    })
+    .await
+    .unwrap();
+    assert_eq!(steps.last().unwrap().concurrency, 8);
+}
+
+#[test]
+fn test_persist_keeps_best_step() {
+    let dir = std::env::temp_dir().join(format!("auto-tune-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out_file = dir.join("result.csv");
+    let step = |concurrency, passed| TuneStep {
+        concurrency,
+        throughput: concurrency as f64,
+        latency: Some(Duration::from_millis(1)),
+        error_rate: 0.0,
+        passed,
+    };
+    let report = AutoTuneReport {
+        slo: "p99<5ms".parse().unwrap(),
+        steps: vec![step(1, true), step(2, true), step(4, false)],
+    };
+    for step in report.steps.iter() {
+        let results = step_path(&out_file, step.concurrency);
+        std::fs::write(&results, step.concurrency.to_string()).unwrap();
+        std::fs::write(Manifest::path_for(&results), "").unwrap();
+    }
+
+    report.persist(&out_file).unwrap();
+    assert_eq!(std::fs::read_to_string(&out_file).unwrap(), "2");
+    assert!(Manifest::path_for(&out_file).exists());
+    let steps = std::fs::read_to_string(out_file.with_extension("steps.csv")).unwrap();
+    assert_eq!(steps.lines().count(), 4);
+    let left: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(left.len(), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
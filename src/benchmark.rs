@@ -1,166 +1,708 @@
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashMap};
 use std::{
     io::Write,
-    net::SocketAddr,
+    ops::Range,
     path::PathBuf,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
+/// Size of the queue of every worker when patterns are partitioned by key.
 const WORKER_CHANNEL_SIZE: usize = 100;
+/// Size of the queue all workers take from otherwise.
+const SHARED_CHANNEL_SIZE: usize = 1000;
+const DECODER_CHANNEL_SIZE: usize = 1000;
 
 use tokio::{sync::Semaphore, task::JoinHandle};
 
-use crate::results::ResultEntry;
+use crate::autotune::{auto_tune, AutoTuneReport, Slo};
+use crate::connection::{Connector, Target};
+use crate::datafile::{DataFile, PatternRange};
+use crate::generator::{generate, GenerateConfig};
+use crate::linearizability::{print_report, History};
+use crate::manifest::{ConfigRecord, DataFileRecord, Manifest};
+use crate::monitor::{monitor_client, ClientCounters, Queues};
+use crate::namespace::{cleanup, KeyNamespace};
+use crate::options::TlsArgs;
+use crate::pattern::basic::BasicCommand;
+use crate::results::{print_summaries, summaries_by_class, ResultEntry, Summary};
+use crate::routing::{Router, Routing};
+use crate::server_monitor::{
+    check_server_pids, monitor_server, print_server_samples, samples_path_for, write_server_samples,
+};
 use crate::supplier::PatternResponse;
+use crate::think_time::ThinkTimes;
 use crate::{
-    supplier::{feed_chans, feed_from_file, PatternBundle},
-    worker::worker,
+    supplier::{feed_from_file, feed_from_memory, feed_partitioned, feed_shared, PatternBundle},
+    worker::{execute_bulk, worker, ConnectionMode},
 };
 
+/// Settings of a benchmark run, merged from the command line and the
+/// scenario file, or built from [`BenchmarkConfig::new`].
+#[derive(Debug, Clone)]
+pub struct BenchmarkConfig {
+    pub(crate) duration: Duration,
+    /// time the benchmark runs before results are recorded
+    pub(crate) warmup: Duration,
+    /// number of workers, derived from the file descriptor limit if `None`
+    pub(crate) concurrency: Option<usize>,
+    pub(crate) connection: ConnectionMode,
+    pub(crate) inp_file: PathBuf,
+    /// replay the patterns from memory rather than decoding them during the
+    /// run
+    pub(crate) in_memory: bool,
+    /// part of the input file that is replayed, all of it if `None`
+    pub(crate) pattern_range: Option<PatternRange>,
+    /// generate the input file before the benchmark starts
+    pub(crate) generate: Option<GenerateConfig>,
+    /// file the results are written to as CSV
+    pub(crate) out_file: Option<PathBuf>,
+    pub(crate) targets: Vec<Target>,
+    pub(crate) routing: Routing,
+    pub(crate) check_linearizability: bool,
+    /// think times overriding the ones stored in the data file
+    pub(crate) think_times: ThinkTimes,
+    /// prefix put in front of every key
+    pub(crate) key_prefix: Option<String>,
+    /// add a random salt to the key prefix, unique to the run
+    pub(crate) salt: bool,
+    /// delete every key the run may have created once it finished
+    pub(crate) cleanup: bool,
+    /// server processes whose resource usage is sampled during the run
+    pub(crate) server_pids: Vec<u32>,
+    /// objective the concurrency is tuned against instead of running once
+    pub(crate) auto_tune: Option<Slo>,
+    pub(crate) tls: TlsArgs,
+    /// limit on open file descriptors, caps the number of workers if the
+    /// concurrency isn't given
+    pub(crate) fd_limit: Option<u64>,
+}
+
+impl BenchmarkConfig {
+    /// Replays the patterns of `inp_file` against `targets` for `duration`.
+    pub fn new(targets: Vec<Target>, inp_file: impl Into<PathBuf>, duration: Duration) -> Self {
+        Self {
+            duration,
+            warmup: Duration::ZERO,
+            concurrency: None,
+            connection: ConnectionMode::PerPattern,
+            inp_file: inp_file.into(),
+            in_memory: false,
+            pattern_range: None,
+            generate: None,
+            out_file: None,
+            targets,
+            routing: Routing::RoundRobin,
+            check_linearizability: false,
+            think_times: ThinkTimes::default(),
+            key_prefix: None,
+            salt: false,
+            cleanup: false,
+            server_pids: Vec::new(),
+            auto_tune: None,
+            tls: TlsArgs::default(),
+            fd_limit: None,
+        }
+    }
+
+    /// Time the benchmark runs before results are recorded.
+    pub fn warmup(mut self, warmup: Duration) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Number of workers executing patterns concurrently.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = Some(concurrency);
+        self
+    }
+
+    pub fn connection(mut self, connection: ConnectionMode) -> Self {
+        self.connection = connection;
+        self
+    }
+
+    /// Decodes the whole input file before the benchmark starts and replays
+    /// the patterns from memory.
+    pub fn in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    /// Replays only the patterns in `range` of the input file, so several
+    /// clients can replay disjoint parts of it. Predictions only hold for
+    /// ranges starting at 0.
+    pub fn pattern_range(mut self, range: PatternRange) -> Self {
+        self.pattern_range = Some(range);
+        self
+    }
+
+    /// Generates the input file before the benchmark starts.
+    pub fn generate(mut self, generate: GenerateConfig) -> Self {
+        self.generate = Some(generate);
+        self
+    }
+
+    /// Writes the results to `out_file` as CSV.
+    pub fn out_file(mut self, out_file: impl Into<PathBuf>) -> Self {
+        self.out_file = Some(out_file.into());
+        self
+    }
+
+    pub fn routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    pub fn check_linearizability(mut self, check_linearizability: bool) -> Self {
+        self.check_linearizability = check_linearizability;
+        self
+    }
+
+    /// Think times overriding the ones stored in the data file.
+    pub fn think_times(mut self, think_times: ThinkTimes) -> Self {
+        self.think_times = think_times;
+        self
+    }
+
+    pub fn key_prefix(mut self, key_prefix: impl Into<String>) -> Self {
+        self.key_prefix = Some(key_prefix.into());
+        self
+    }
+
+    /// Adds a random salt unique to the run to the key prefix.
+    pub fn salt(mut self, salt: bool) -> Self {
+        self.salt = salt;
+        self
+    }
+
+    /// Deletes every key the run may have created once it finished.
+    pub fn cleanup(mut self, cleanup: bool) -> Self {
+        self.cleanup = cleanup;
+        self
+    }
+
+    /// Samples the CPU time, memory, threads and open file descriptors of
+    /// the server process `pid` during the run, written next to the results.
+    pub fn server_pid(mut self, pid: u32) -> Self {
+        self.server_pids.push(pid);
+        self
+    }
+
+    /// Searches for the highest concurrency meeting `slo` when run with
+    /// [`BenchmarkConfig::tune`].
+    pub fn auto_tune(mut self, slo: Slo) -> Self {
+        self.auto_tune = Some(slo);
+        self
+    }
+
+    /// Whether an objective to tune against was set.
+    pub fn is_auto_tune(&self) -> bool {
+        self.auto_tune.is_some()
+    }
+
+    pub fn tls(mut self, tls: TlsArgs) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn fd_limit(mut self, fd_limit: u64) -> Self {
+        self.fd_limit = Some(fd_limit);
+        self
+    }
+
+    pub async fn run(self) -> Result<BenchmarkReport, Box<dyn std::error::Error + Send + Sync>> {
+        perform_benchmark(self).await
+    }
+
+    /// Runs the benchmark at increasing concurrency until the highest one
+    /// meeting the objective set with [`BenchmarkConfig::auto_tune`] is found.
+    pub async fn tune(self) -> Result<AutoTuneReport, Box<dyn std::error::Error + Send + Sync>> {
+        let slo = self
+            .auto_tune
+            .ok_or("no objective to tune against was set")?;
+        auto_tune(self, slo).await
+    }
+}
+
+/// Results of a benchmark run, excluding the patterns executed during the
+/// warm-up.
+#[derive(Debug)]
+pub struct BenchmarkReport {
+    /// responses sorted by start time
+    pub responses: Vec<PatternResponse>,
+    /// summary per host, in the order the hosts were given
+    pub hosts: Vec<(String, Summary)>,
+    /// summary per pattern class, sorted by class
+    pub patterns: Vec<(String, Summary)>,
+    /// number of linearizability violations, if the history was checked
+    pub violations: Option<usize>,
+}
+
+impl BenchmarkReport {
+    pub fn print(&mut self) {
+        print_summaries("host", &mut self.hosts);
+        print_summaries("pattern", &mut self.patterns);
+    }
+}
+
 pub(crate) async fn perform_benchmark(
-    duration: Duration,
-    inp_file: PathBuf,
-    out_file: PathBuf,
-    host: SocketAddr,
-    fd_limit: u64,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let workers_num = fd_limit_to_worker_num(fd_limit);
+    mut config: BenchmarkConfig,
+) -> Result<BenchmarkReport, Box<dyn std::error::Error + Send + Sync>> {
+    if config.routing == Routing::Hash {
+        if let Some(generate) = config.generate.as_mut() {
+            generate.routing = Routing::Hash;
+        }
+    }
+    let config_record = ConfigRecord::from(&config);
+    let BenchmarkConfig {
+        duration,
+        warmup,
+        concurrency,
+        connection,
+        inp_file,
+        in_memory,
+        pattern_range,
+        generate: generate_config,
+        out_file,
+        targets,
+        routing,
+        check_linearizability,
+        think_times,
+        key_prefix,
+        salt,
+        cleanup: cleanup_keys,
+        server_pids,
+        auto_tune: _,
+        tls,
+        fd_limit,
+    } = config;
+
+    if let Some(generate_config) = generate_config {
+        println!("generating {:?}", generate_config.data_out);
+        generate(generate_config).await?;
+    }
+
+    let connector_arc = Arc::new(Connector::new(targets, &tls)?);
+    check_server_pids(&server_pids)?;
+
+    let workers_num = concurrency.unwrap_or_else(|| fd_limit_to_worker_num(fd_limit));
 
     println!("creating {} workers", workers_num);
 
+    let data_file = DataFile::open(&inp_file).await?;
+    let metadata = data_file.metadata.clone();
+    let data_file_record = DataFileRecord::from(&metadata);
+    let range = pattern_range
+        .unwrap_or_default()
+        .resolve(data_file.patterns())?;
+    if pattern_range.is_some() {
+        println!(
+            "replaying patterns {}..{} of {}",
+            range.start,
+            range.end,
+            data_file.patterns()
+        );
+    }
+    if range.start > 0 {
+        println!(
+            "warning: the predictions of patterns {}.. assume patterns 0..{} ran before them, expect mispredictions",
+            range.start, range.start
+        );
+    }
+    if metadata.partitions > 0 {
+        println!(
+            "data file is partitioned into {} key partitions",
+            metadata.partitions
+        );
+        if (metadata.partitions as usize) < workers_num {
+            println!(
+                "only {} of {} workers will receive patterns, generate the file with {} partitions to use all of them",
+                metadata.partitions, workers_num, workers_num
+            );
+        }
+    }
+
+    if let Some(value_size) = &metadata.value_size {
+        println!("value sizes of the data file follow {}", value_size);
+    }
+
+    let think_times = think_times.or(metadata.think_times);
+    if let Some(think_time) = think_times.command {
+        println!("thinking {} between commands", think_time);
+    }
+    if let Some(think_time) = think_times.pattern {
+        println!("thinking {} between patterns", think_time);
+    }
+
+    let namespace = KeyNamespace::new(key_prefix, salt);
+    if !namespace.is_empty() {
+        println!("prefixing all keys with {:?}", namespace.prefix());
+    }
+
+    let preloaded: HashMap<String, String> = data_file
+        .read_preload()
+        .await?
+        .into_iter()
+        .map(|(key, value)| (namespace.key(&key), value))
+        .collect();
+    let partitioned = metadata.partitions > 0;
+    if !preloaded.is_empty() && !partitioned {
+        return Err(format!(
+            "{:?} preloads keys but isn't partitioned, patterns on the same key could run concurrently, regenerate it with --partitions",
+            inp_file
+        )
+        .into());
+    }
+    if routing == Routing::Hash && connector_arc.targets.len() > 1 {
+        check_hash_routing(
+            &data_file,
+            range.clone(),
+            &namespace,
+            &connector_arc.targets,
+        )
+        .await?;
+    }
+    let patterns = if in_memory {
+        let mut patterns = data_file.read_patterns(range.clone()).await?;
+        for pattern in patterns.iter_mut() {
+            namespace.apply(pattern);
+        }
+        println!("loaded {} patterns into memory", patterns.len());
+        Some(patterns.into_iter().map(Arc::new).collect::<Vec<_>>())
+    } else {
+        None
+    };
+    if !preloaded.is_empty() {
+        println!("preloading {} keys", preloaded.len());
+        let commands = preloaded
+            .iter()
+            .map(|(key, value)| BasicCommand::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        let existed = execute_bulk(commands, connector_arc.clone(), routing, workers_num).await?;
+        if existed > 0 {
+            println!(
+                "{} preloaded keys already existed, predictions may be off if the server is shared",
+                existed
+            );
+        }
+    }
+
     let start_time = std::time::Instant::now();
+    let start_wall_clock = SystemTime::now();
 
     let (kill_switch_sender, mut kill_switch_receiver) = tokio::sync::watch::channel(());
     kill_switch_receiver.borrow_and_update();
 
-    let (decoder_sender, decoder_receiver) = tokio::sync::mpsc::channel(1000);
+    let (decoder_sender, decoder_receiver) = tokio::sync::mpsc::channel(DECODER_CHANNEL_SIZE);
+    let counters = Arc::new(ClientCounters::default());
+    let monitored_decoder = decoder_sender.clone();
 
+    let decoder_file = data_file.clone();
+    let decoder_range = range.clone();
+    let decoder_namespace = namespace.clone();
+    let decoder_counters = counters.clone();
     let decoder_handle = tokio::spawn(async move {
-        let res = feed_from_file(inp_file, decoder_sender).await;
-        println!("from file feader died");
-        res
+        match patterns {
+            Some(patterns) => feed_from_memory(patterns, decoder_sender, decoder_counters).await,
+            None => {
+                feed_from_file(
+                    decoder_file,
+                    decoder_range,
+                    decoder_sender,
+                    decoder_namespace,
+                    decoder_counters,
+                )
+                .await
+            }
+        }
     });
 
     println!("started decoder");
 
-    let (worker_senders, worker_receivers) = make_worker_chans(workers_num);
+    let (worker_senders, worker_receivers) = make_worker_chans(workers_num, partitioned);
 
     println!("created worker chans");
 
+    let server_monitor_handle = tokio::spawn(monitor_server(
+        server_pids,
+        start_time.into(),
+        kill_switch_receiver.clone(),
+    ));
+
+    let monitor_handle = tokio::spawn(monitor_client(
+        counters.clone(),
+        Queues {
+            decoder: monitored_decoder,
+            decoder_size: DECODER_CHANNEL_SIZE,
+            workers: worker_senders.clone(),
+        },
+        kill_switch_receiver.clone(),
+    ));
+
     let activator = Arc::new(Semaphore::new(0));
-    let host_arc = Arc::new(host);
+    let router = Router::new(routing, &connector_arc.targets);
 
     let workers = make_workers(
         worker_receivers,
-        host_arc.clone(),
+        connector_arc.clone(),
         activator.clone(),
         kill_switch_receiver.clone(),
+        connection,
+        think_times,
+        counters,
     );
 
     println!("created workers");
 
     let feeder_handle = tokio::spawn(async move {
-        let res = feed_chans::<false>(
-            decoder_receiver,
-            worker_senders,
-            kill_switch_receiver.clone(),
-        )
-        .await;
-        println!("channel feeder quit");
-        res
+        if partitioned {
+            feed_partitioned(
+                decoder_receiver,
+                worker_senders,
+                kill_switch_receiver.clone(),
+                router,
+            )
+            .await
+        } else {
+            let shared = worker_senders.into_iter().next().unwrap();
+            feed_shared(
+                decoder_receiver,
+                shared,
+                kill_switch_receiver.clone(),
+                router,
+            )
+            .await
+        }
     });
 
+    let run_time = warmup + duration;
     let killer_handle = tokio::spawn(async move {
-        println!("the killer is awake {:?}", duration);
         let now = Instant::now();
-        let bar = indicatif::ProgressBar::new(duration.as_secs() - 1);
-        while now.elapsed() < duration {
+        let bar = indicatif::ProgressBar::new(run_time.as_secs().saturating_sub(1));
+        while now.elapsed() < run_time {
             bar.set_position(now.elapsed().as_secs());
             tokio::time::sleep(Duration::from_millis(500)).await;
         }
         bar.finish_and_clear();
-        println!("Killing!!");
-        kill_switch_sender.send(()).unwrap();
+        // the workers might all have stopped already
+        let _ = kill_switch_sender.send(());
     });
 
     activator.add_permits(workers_num * 10);
 
     let mut all_results = BinaryHeap::new();
     for worker in workers {
-        let mut m = worker.await.unwrap().unwrap();
-        all_results.append(&mut m);
+        match worker.await? {
+            Ok(mut results) => all_results.append(&mut results),
+            Err(e) => {
+                // dropping the kill switch stops the remaining tasks
+                killer_handle.abort();
+                decoder_handle.abort();
+                return Err(e);
+            }
+        }
     }
 
-    killer_handle.await.expect("failed to join killer task");
+    killer_handle.await?;
 
     decoder_handle.abort();
-    // decoder_handle.await??;
-    // resp_handler_handle.abort();
-    feeder_handle.await.unwrap().unwrap();
+    feeder_handle.await??;
+    let client_report = monitor_handle.await?;
+    let server_samples = server_monitor_handle.await?;
 
     println!("finished benchmark");
-
-    let mut out_file = std::fs::File::create(out_file)?;
+    client_report.print();
+    print_server_samples(&server_samples);
+
+    let host_names: Vec<String> = connector_arc
+        .targets
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let mut hosts: Vec<(String, Summary)> = host_names
+        .iter()
+        .map(|name| (name.clone(), Summary::default()))
+        .collect();
 
     let responses = all_results.into_sorted_vec();
-    responses
+
+    let violations = if check_linearizability {
+        let history =
+            History::from_responses(responses.iter(), start_time.into()).with_initial(preloaded);
+        let violations = history.check();
+        print_report(&history, &violations);
+        Some(violations.len())
+    } else {
+        None
+    };
+
+    let recording_start: tokio::time::Instant = (start_time + warmup).into();
+    let (responses, warmup_responses): (Vec<_>, Vec<_>) = responses
         .into_iter()
-        .map(|e| ResultEntry {
-            pattern: e.pattern,
-            durations: e.timing.durations,
-            total_duration: e.timing.total_duration,
-            start_time: e.timing.start_time,
-        })
-        .map(|e| e.to_csv_line(start_time.into()))
-        .for_each(|mut e| {
-            e.push('\n');
-            out_file.write_all(e.as_bytes()).unwrap();
-        });
+        .partition(|e| e.timing.start_time >= recording_start);
+    if !warmup.is_zero() {
+        println!(
+            "discarded {} patterns executed during the warm-up",
+            warmup_responses.len()
+        );
+    }
+
+    let patterns = summaries_by_class(responses.iter());
+    for response in responses.iter() {
+        hosts[response.target].1.add(&response.timing);
+    }
 
-    std::process::exit(0);
+    if let Some(out_file) = out_file {
+        let manifest = Manifest::new(
+            config_record,
+            data_file_record,
+            client_report,
+            workers_num,
+            start_wall_clock,
+            SystemTime::now(),
+        );
+        let manifest_path = Manifest::path_for(&out_file);
+        manifest.write(&manifest_path)?;
+        println!("wrote the run manifest to {:?}", manifest_path);
+
+        if !server_samples.is_empty() {
+            let samples_path = samples_path_for(&out_file);
+            write_server_samples(&samples_path, &server_samples)?;
+            println!("wrote the server samples to {:?}", samples_path);
+        }
+
+        let mut out_file = std::io::BufWriter::new(std::fs::File::create(out_file)?);
+        let commands = responses
+            .iter()
+            .map(|response| response.pattern.0.len())
+            .max()
+            .unwrap_or(0);
+        writeln!(out_file, "{}", ResultEntry::csv_header(commands))?;
+        for response in responses.iter() {
+            let entry = ResultEntry {
+                pattern: &response.pattern,
+                timing: &response.timing,
+                host: &host_names[response.target],
+            };
+            let mut line = entry.to_csv_line(start_time.into());
+            line.push('\n');
+            out_file.write_all(line.as_bytes())?;
+        }
+        out_file.flush()?;
+    }
+
+    if cleanup_keys {
+        let existed = cleanup(
+            &data_file,
+            range,
+            &namespace,
+            connector_arc,
+            routing,
+            workers_num,
+        )
+        .await?;
+        println!("cleanup deleted {} keys that still existed", existed);
+    }
+
+    Ok(BenchmarkReport {
+        responses,
+        hosts,
+        patterns,
+        violations,
+    })
 }
 
-fn fd_limit_to_worker_num(fd_limit: u64) -> usize {
-    let concurrency_available = std::thread::available_parallelism().unwrap().get() * 4;
-    let tmp = fd_limit as usize;
+/// Checks that hash routing sends every pattern of the range to a single
+/// host, so a pattern spanning hosts is rejected before the run rather than
+/// stopping it midway.
+async fn check_hash_routing(
+    data_file: &DataFile,
+    range: Range<u64>,
+    namespace: &KeyNamespace,
+    targets: &[Target],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut router = Router::new(Routing::Hash, targets);
+    for chunk in data_file.chunk_of(range.start)..=data_file.chunk_of(range.end - 1) {
+        let first = data_file.chunks()[chunk].first_pattern;
+        for (position, mut pattern) in (first..).zip(data_file.read_chunk(chunk).await?) {
+            if !range.contains(&position) {
+                continue;
+            }
+            namespace.apply(&mut pattern);
+            router.route(&pattern).map_err(|e| {
+                format!(
+                    "pattern {} can't be hash routed => {}, generate the file with --routing hash",
+                    position, e
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn fd_limit_to_worker_num(fd_limit: Option<u64>) -> usize {
+    let concurrency_available = std::thread::available_parallelism().map_or(1, |n| n.get()) * 4;
+    let tmp = fd_limit.map_or(usize::MAX, |limit| limit as usize);
     concurrency_available.min(tmp)
 }
 
+/// Queues the workers take their patterns from, one per worker if patterns
+/// are partitioned by key and a single shared one otherwise.
 fn make_worker_chans(
     workers: usize,
+    partitioned: bool,
 ) -> (
-    Vec<tokio::sync::mpsc::Sender<PatternBundle>>,
-    Vec<tokio::sync::mpsc::Receiver<PatternBundle>>,
+    Vec<flume::Sender<PatternBundle>>,
+    Vec<flume::Receiver<PatternBundle>>,
 ) {
+    if !partitioned {
+        let (sender, receiver) = flume::bounded(SHARED_CHANNEL_SIZE);
+        return (vec![sender], vec![receiver; workers]);
+    }
     let mut senders = Vec::with_capacity(workers);
     let mut receivers = Vec::with_capacity(workers);
     for _ in 0..workers {
-        let (sender, receiver) = tokio::sync::mpsc::channel(WORKER_CHANNEL_SIZE);
+        let (sender, receiver) = flume::bounded(WORKER_CHANNEL_SIZE);
         senders.push(sender);
         receivers.push(receiver);
     }
     (senders, receivers)
 }
 
+type WorkerHandle =
+    JoinHandle<Result<BinaryHeap<PatternResponse>, Box<dyn std::error::Error + Send + Sync>>>;
+
 fn make_workers(
-    worker_receivers: Vec<tokio::sync::mpsc::Receiver<PatternBundle>>,
-    host: Arc<SocketAddr>,
+    worker_receivers: Vec<flume::Receiver<PatternBundle>>,
+    connector: Arc<Connector>,
     activator: Arc<Semaphore>,
     kill_switch: tokio::sync::watch::Receiver<()>,
-) -> Vec<JoinHandle<Result<BinaryHeap<PatternResponse>, Box<dyn std::error::Error + Send + Sync>>>>
-{
+    mode: ConnectionMode,
+    think_times: ThinkTimes,
+    counters: Arc<ClientCounters>,
+) -> Vec<WorkerHandle> {
     let mut ret = Vec::with_capacity(worker_receivers.len());
 
     for r in worker_receivers {
-        let local_host = host.clone();
+        let local_connector = connector.clone();
         let local_activator = activator.clone();
         let local_kill_switch = kill_switch.clone();
+        let local_counters = counters.clone();
         let worker_handle = tokio::spawn(async move {
-            let inner_host = local_host.clone();
-            let res = worker(r, *inner_host, local_kill_switch, local_activator).await;
-
-            res
+            worker(
+                r,
+                local_connector,
+                local_kill_switch,
+                local_activator,
+                mode,
+                think_times,
+                local_counters,
+            )
+            .await
         });
 
         ret.push(worker_handle);
@@ -0,0 +1,177 @@
+use std::{fs::File, net::SocketAddr, path::PathBuf};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::UnboundedSender,
+};
+
+use crate::{
+    datafile::{DataFileMetadata, PatternWriter},
+    pattern::{
+        basic::{BasicCommand, BasicPattern},
+        ExecPattern,
+    },
+};
+
+const CAPTURE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Runs a recording proxy between clients and `upstream`.
+///
+/// Every connection is turned into one pattern, consisting of the commands
+/// the client sent and the responses the server answered with. Patterns are
+/// written to `data_out` once their connection is closed. Capturing stops
+/// after `limit` patterns or when the process receives Ctrl-C.
+pub async fn capture(
+    listen: SocketAddr,
+    upstream: SocketAddr,
+    data_out: PathBuf,
+    compression_level: i32,
+    limit: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(listen).await?;
+    println!("capturing {} -> {}", listener.local_addr()?, upstream);
+
+    let file = File::create(data_out)?;
+    let mut writer = PatternWriter::new(file, compression_level, &DataFileMetadata::default())?;
+
+    let (pattern_sender, mut pattern_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    let mut written = 0usize;
+    while limit.is_none_or(|l| written < l) {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (client, _) = accepted?;
+                let local_sender = pattern_sender.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = record_connection(client, upstream, local_sender).await {
+                        println!("recording connection failed => {:?}", e);
+                    }
+                });
+            }
+            pattern = pattern_receiver.recv() => {
+                writer.write_pattern(&pattern.unwrap())?;
+                written += 1;
+            }
+            _ = &mut ctrl_c => {
+                println!("received interrupt, stopping capture");
+                break;
+            }
+        }
+    }
+
+    writer.finish()?;
+    println!("captured {} patterns", written);
+
+    Ok(())
+}
+
+async fn record_connection(
+    mut client: TcpStream,
+    upstream: SocketAddr,
+    patterns: UnboundedSender<ExecPattern>,
+) -> std::io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+
+    let mut commands = Vec::new();
+    let mut responses = Vec::new();
+    let (upstream_result, downstream_result) = {
+        let (mut client_read, mut client_write) = client.split();
+        let (mut server_read, mut server_write) = server.split();
+        tokio::join!(
+            record(&mut client_read, &mut server_write, &mut commands),
+            record(&mut server_read, &mut client_write, &mut responses),
+        )
+    };
+
+    if let Some(pattern) = build_pattern(commands, responses) {
+        // the receiver is gone once the capture stopped, the pattern is lost then
+        let _ = patterns.send(pattern);
+    }
+
+    upstream_result.and(downstream_result)
+}
+
+/// Forwards everything from `src` to `dst` and collects the complete lines
+/// that passed through, including their trailing newline. `dst` is shut down
+/// once `src` is exhausted or failed, so the other direction terminates too.
+async fn record<R, W>(src: &mut R, dst: &mut W, lines: &mut Vec<String>) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let res = forward_lines(src, dst, lines).await;
+    // the peer might already be gone, there is nothing left to tell it then
+    let _ = dst.shutdown().await;
+    res
+}
+
+async fn forward_lines<R, W>(
+    src: &mut R,
+    dst: &mut W,
+    lines: &mut Vec<String>,
+) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; CAPTURE_BUFFER_SIZE];
+    let mut partial = Vec::new();
+    loop {
+        let read = src.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        dst.write_all(&buf[..read]).await?;
+        dst.flush().await?;
+
+        partial.extend_from_slice(&buf[..read]);
+        while let Some(pos) = partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = partial.drain(..=pos).collect();
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+    }
+}
+
+/// Pairs the commands of a connection with the responses in order. Commands
+/// the server never answered are left out.
+fn build_pattern(commands: Vec<String>, responses: Vec<String>) -> Option<ExecPattern> {
+    let mut parsed = Vec::with_capacity(commands.len());
+    for line in commands.iter().take(responses.len()) {
+        match line.parse::<BasicCommand>() {
+            Ok(command) => parsed.push(command),
+            Err(e) => {
+                println!(
+                    "dropping connection with unparsable command {:?} => {}",
+                    line, e
+                );
+                return None;
+            }
+        }
+    }
+
+    if parsed.is_empty() {
+        return None;
+    }
+
+    let predictions = responses.into_iter().take(parsed.len()).collect();
+    Some(BasicPattern(parsed, predictions, None))
+}
+
+#[test]
+fn test_build_pattern() {
+    let commands = vec![
+        "SET a b\n".to_string(),
+        "GET a\n".to_string(),
+        "DEL a\n".to_string(),
+    ];
+    let responses = vec!["not found\n".to_string(), "b\n".to_string()];
+    let pattern = build_pattern(commands, responses).unwrap();
+    assert_eq!(pattern.0.len(), 2);
+    assert_eq!(pattern.0[1].to_string(), "GET a");
+    assert_eq!(pattern.1, vec!["not found\n", "b\n"]);
+
+    assert!(build_pattern(vec!["FOO\n".to_string()], vec!["x\n".to_string()]).is_none());
+}
@@ -0,0 +1,331 @@
+use std::collections::{hash_map::Entry, HashMap};
+use std::time::Duration;
+
+use comfy_table::Table;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+
+use crate::connection::Connector;
+use crate::generator::generate_valid_string;
+use crate::pattern::basic::BasicCommand;
+use crate::worker::{connect, Stream};
+
+const NOT_FOUND: &str = "not found";
+/// Time the server has to answer a single command.
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+const MANY_KEYS: usize = 1_000;
+const REPEATED_CONNECTIONS: usize = 50;
+
+/// A command of a conformance case and the response the protocol demands.
+struct Step {
+    /// connection the command is sent on, numbered per case
+    connection: usize,
+    command: BasicCommand,
+    expected: String,
+}
+
+/// A named check of one aspect of the protocol, made of steps executed in
+/// order.
+struct Case {
+    name: &'static str,
+    steps: Vec<Step>,
+}
+
+/// The step a case failed at.
+struct Failure {
+    step: usize,
+    command: String,
+    expected: String,
+    actual: String,
+}
+
+fn step(command: BasicCommand, expected: &str) -> Step {
+    Step {
+        connection: 0,
+        command,
+        expected: expected.to_string(),
+    }
+}
+
+fn get(key: &str) -> BasicCommand {
+    BasicCommand::Get {
+        key: key.to_string(),
+    }
+}
+
+fn set(key: &str, value: &str) -> BasicCommand {
+    BasicCommand::Set {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+fn del(key: &str) -> BasicCommand {
+    BasicCommand::Del {
+        key: key.to_string(),
+    }
+}
+
+/// The catalogue of cases. Keys are prefixed with a random salt, so cases
+/// don't depend on what the server stored before.
+fn cases(max_key_size: usize, max_value_size: usize) -> Vec<Case> {
+    let salt = generate_valid_string(8);
+    let key = |name: &str| format!("{}{}", salt, name);
+
+    let fresh = key("fresh");
+    let overwritten = key("overwritten");
+    let deleted = key("deleted");
+    let reread = key("reread");
+    let missing = key("missing");
+    // the salt is cut short if the limit doesn't leave room for it
+    let long_key_salt = &salt[..salt.len().min(max_key_size)];
+    let long_key = format!(
+        "{}{}",
+        long_key_salt,
+        generate_valid_string(max_key_size - long_key_salt.len())
+    );
+    let long_value = generate_valid_string(max_value_size);
+    let long_value_key = key("longvalue");
+    let shared = key("shared");
+
+    let many: Vec<String> = (0..MANY_KEYS).map(|i| key(&format!("many{}", i))).collect();
+    let mut many_steps: Vec<Step> = many
+        .iter()
+        .map(|k| step(set(k, &format!("v{}", k)), NOT_FOUND))
+        .collect();
+    many_steps.extend(many.iter().map(|k| step(get(k), &format!("v{}", k))));
+
+    let mut connection_steps = vec![step(set(&shared, "a"), NOT_FOUND)];
+    connection_steps.extend((1..=REPEATED_CONNECTIONS).map(|connection| Step {
+        connection,
+        command: get(&shared),
+        expected: "a".to_string(),
+    }));
+    connection_steps.push(Step {
+        connection: REPEATED_CONNECTIONS + 1,
+        command: del(&shared),
+        expected: "a".to_string(),
+    });
+
+    vec![
+        Case {
+            name: "GET of a missing key",
+            steps: vec![step(get(&missing), NOT_FOUND)],
+        },
+        Case {
+            name: "SET of a new key",
+            steps: vec![step(set(&fresh, "a"), NOT_FOUND), step(get(&fresh), "a")],
+        },
+        Case {
+            name: "overwrite returns the old value",
+            steps: vec![
+                step(set(&overwritten, "a"), NOT_FOUND),
+                step(set(&overwritten, "b"), "a"),
+                step(get(&overwritten), "b"),
+            ],
+        },
+        Case {
+            name: "DEL returns the value",
+            steps: vec![
+                step(set(&deleted, "a"), NOT_FOUND),
+                step(del(&deleted), "a"),
+            ],
+        },
+        Case {
+            name: "GET after DEL",
+            steps: vec![
+                step(set(&reread, "a"), NOT_FOUND),
+                step(del(&reread), "a"),
+                step(get(&reread), NOT_FOUND),
+            ],
+        },
+        Case {
+            name: "DEL of a missing key",
+            steps: vec![step(del(&missing), NOT_FOUND)],
+        },
+        Case {
+            name: "maximum key length",
+            steps: vec![
+                step(set(&long_key, "a"), NOT_FOUND),
+                step(get(&long_key), "a"),
+                step(del(&long_key), "a"),
+            ],
+        },
+        Case {
+            name: "maximum value length",
+            steps: vec![
+                step(set(&long_value_key, &long_value), NOT_FOUND),
+                step(get(&long_value_key), &long_value),
+                step(del(&long_value_key), &long_value),
+            ],
+        },
+        Case {
+            name: "many keys",
+            steps: many_steps,
+        },
+        Case {
+            name: "values survive repeated connections",
+            steps: connection_steps,
+        },
+    ]
+}
+
+async fn run_case(connector: &Connector, case: &Case) -> Result<(), Failure> {
+    let mut connections: HashMap<usize, BufStream<Box<dyn Stream>>> = HashMap::new();
+    let mut response = String::new();
+
+    for (idx, step) in case.steps.iter().enumerate() {
+        let failure = |actual: String| Failure {
+            step: idx + 1,
+            command: step.command.to_string(),
+            expected: step.expected.clone(),
+            actual,
+        };
+
+        let connection = match connections.entry(step.connection) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let (stream, _) = connect(connector, &connector.targets[0])
+                    .await
+                    .map_err(|e| failure(format!("couldn't connect => {}", e)))?;
+                entry.insert(BufStream::new(stream))
+            }
+        };
+
+        response.clear();
+        let exchange = async {
+            connection
+                .write_all(format!("{}\n", step.command).as_bytes())
+                .await?;
+            connection.flush().await?;
+            connection.read_line(&mut response).await
+        };
+        match tokio::time::timeout(STEP_TIMEOUT, exchange).await {
+            Err(_) => return Err(failure(format!("no response within {:?}", STEP_TIMEOUT))),
+            Ok(Err(e)) => return Err(failure(format!("io error => {}", e))),
+            Ok(Ok(0)) => return Err(failure("connection closed".to_string())),
+            Ok(Ok(_)) => {}
+        }
+
+        let actual = response.trim_end_matches('\n');
+        if actual != step.expected {
+            return Err(failure(actual.to_string()));
+        }
+    }
+
+    for (_, connection) in connections {
+        // the server might have closed the connection already
+        let _ = connection.into_inner().shutdown().await;
+    }
+    Ok(())
+}
+
+/// Runs every case against the first host, failing if any of them failed.
+pub async fn run_conformance(
+    connector: &Connector,
+    max_key_size: usize,
+    max_value_size: usize,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if max_key_size == 0 || max_value_size == 0 {
+        return Err("the maximum key and value sizes have to be at least 1".into());
+    }
+    let cases = cases(max_key_size, max_value_size);
+
+    let mut table = Table::new();
+    table.set_header(vec!["case", "result", "details"]);
+    let mut passed = 0;
+    for case in cases.iter() {
+        match run_case(connector, case).await {
+            Ok(()) => {
+                passed += 1;
+                table.add_row(vec![case.name, "pass", ""]);
+            }
+            Err(failure) => {
+                let details = format!(
+                    "step {}: {}\nexpected: {:?}\nactual: {:?}",
+                    failure.step,
+                    shorten(&failure.command),
+                    shorten(&failure.expected),
+                    shorten(&failure.actual)
+                );
+                table.add_row(vec![case.name, "FAIL", &details]);
+            }
+        }
+    }
+
+    println!("{table}");
+    println!("{} of {} cases passed", passed, cases.len());
+    if passed < cases.len() {
+        return Err(format!("{} conformance cases failed", cases.len() - passed).into());
+    }
+    Ok(())
+}
+
+/// Long keys and values are cut, so the report stays readable.
+fn shorten(s: &str) -> String {
+    const MAX: usize = 60;
+    if s.chars().count() <= MAX {
+        s.to_string()
+    } else {
+        let start: String = s.chars().take(MAX).collect();
+        format!("{}... ({} bytes)", start, s.len())
+    }
+}
+
+#[tokio::test]
+async fn test_conformance_against_reference_server() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::connection::Target;
+    use crate::options::TlsArgs;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let store: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let store = store.clone();
+            tokio::spawn(async move {
+                let mut conn = BufStream::new(conn);
+                let mut line = String::new();
+                while conn.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    let response = match line.parse::<BasicCommand>().unwrap() {
+                        BasicCommand::Get { key } => store.lock().unwrap().get(&key).cloned(),
+                        BasicCommand::Set { key, value } => {
+                            store.lock().unwrap().insert(key, value)
+                        }
+                        BasicCommand::Del { key } => store.lock().unwrap().remove(&key),
+                    };
+                    let response = response.unwrap_or_else(|| NOT_FOUND.to_string());
+                    conn.write_all(format!("{}\n", response).as_bytes())
+                        .await
+                        .unwrap();
+                    conn.flush().await.unwrap();
+                    line.clear();
+                }
+            });
+        }
+    });
+
+    let args = TlsArgs {
+        tls: false,
+        tls_ca: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_sni: None,
+    };
+    let connector = Connector::new(vec![Target::Tcp(address)], &args).unwrap();
+    run_conformance(&connector, 64, 1024).await.unwrap();
+
+    let long_key = cases(4, 16)
+        .into_iter()
+        .find(|case| case.name == "maximum key length")
+        .unwrap()
+        .steps[0]
+        .command
+        .key()
+        .len();
+    assert_eq!(long_key, 4);
+    run_conformance(&connector, 4, 16).await.unwrap();
+    assert!(run_conformance(&connector, 0, 16).await.is_err());
+}
@@ -0,0 +1,174 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use tokio_rustls::{
+    rustls::{self, Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore},
+    TlsConnector,
+};
+
+use crate::options::TlsArgs;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a server under test.
+///
+/// Parsed from either a socket address (`127.0.0.1:8080`) or a path to a
+/// Unix domain socket prefixed with `unix:` (`unix:/tmp/server.sock`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            return Ok(Self::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(format!(
+                "unix domain sockets are not supported on this platform ({})",
+                path
+            ));
+        }
+
+        s.parse::<SocketAddr>()
+            .map(Self::Tcp)
+            .map_err(|e| format!("invalid target {:?} => {}", s, e))
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Target::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Everything a worker needs to open a connection to the servers under test.
+pub struct Connector {
+    pub(crate) targets: Vec<Target>,
+    pub(crate) tls: Option<TlsClient>,
+}
+
+impl Connector {
+    pub fn new(
+        targets: Vec<Target>,
+        tls_args: &TlsArgs,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let tls = if tls_args.tls {
+            Some(TlsClient::new(tls_args)?)
+        } else {
+            None
+        };
+        Ok(Self { targets, tls })
+    }
+}
+
+/// TLS client configuration shared by all connections of a run.
+pub(crate) struct TlsClient {
+    pub(crate) connector: TlsConnector,
+    sni: Option<rustls::ServerName>,
+}
+
+impl TlsClient {
+    pub(crate) fn new(args: &TlsArgs) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut roots = RootCertStore::empty();
+        match &args.tls_ca {
+            Some(path) => {
+                for cert in read_certificates(path)? {
+                    roots.add(&cert)?;
+                }
+            }
+            None => {
+                roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+            }
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => {
+                builder.with_client_auth_cert(read_certificates(cert)?, read_private_key(key)?)?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+
+        let sni = match &args.tls_sni {
+            Some(name) => Some(rustls::ServerName::try_from(name.as_str())?),
+            None => None,
+        };
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            sni,
+        })
+    }
+
+    /// The name the server certificate is verified against, either the one
+    /// passed via SNI override or derived from the target.
+    pub(crate) fn server_name(&self, target: &Target) -> rustls::ServerName {
+        match (&self.sni, target) {
+            (Some(name), _) => name.clone(),
+            (None, Target::Tcp(addr)) => rustls::ServerName::IpAddress(addr.ip()),
+            #[cfg(unix)]
+            (None, Target::Unix(_)) => rustls::ServerName::try_from("localhost").unwrap(),
+        }
+    }
+}
+
+fn read_certificates(path: &Path) -> std::io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> std::io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("no private key found in {}", path.display()),
+    ))
+}
+
+#[test]
+fn test_parse_target() {
+    assert_eq!(
+        "127.0.0.1:8080".parse::<Target>().unwrap(),
+        Target::Tcp("127.0.0.1:8080".parse().unwrap())
+    );
+    #[cfg(unix)]
+    assert_eq!(
+        "unix:/tmp/server.sock".parse::<Target>().unwrap(),
+        Target::Unix(PathBuf::from("/tmp/server.sock"))
+    );
+    assert!("localhost".parse::<Target>().is_err());
+}
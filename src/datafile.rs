@@ -0,0 +1,480 @@
+use std::fmt::Display;
+use std::io::{BufWriter, ErrorKind, Result as IoResult, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::pattern::ExecPattern;
+use crate::think_time::ThinkTimes;
+use crate::value_size::ValueSize;
+
+/// Version of the data file layout, bumped whenever old files can no longer
+/// be read.
+pub(crate) const DATA_FILE_VERSION: u32 = 5;
+
+/// Number of patterns compressed together into one chunk.
+pub(crate) const CHUNK_PATTERNS: u64 = 4096;
+
+/// Last bytes of every data file, preceded by the length of the chunk index.
+const INDEX_MAGIC: &[u8; 8] = b"SLCINDEX";
+
+/// Describes how the patterns of a data file were produced. Stored as the
+/// first record of every data file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DataFileMetadata {
+    pub(crate) version: u32,
+    /// number of disjoint key partitions the patterns were generated for,
+    /// `0` if the key space isn't partitioned
+    pub(crate) partitions: u32,
+    /// pauses the benchmark makes unless overridden at run time
+    pub(crate) think_times: ThinkTimes,
+    /// distribution the sizes of the values were drawn from, `None` for
+    /// captured traffic
+    pub(crate) value_size: Option<ValueSize>,
+    /// number of key value pairs following the metadata, which are set
+    /// before the benchmark starts
+    pub(crate) preload: u64,
+}
+
+impl Default for DataFileMetadata {
+    fn default() -> Self {
+        Self {
+            version: DATA_FILE_VERSION,
+            partitions: 0,
+            think_times: ThinkTimes::default(),
+            value_size: None,
+            preload: 0,
+        }
+    }
+}
+
+/// A range of records compressed as one zstd frame.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct Chunk {
+    /// position of the compressed chunk in the file
+    offset: u64,
+    /// length of the compressed chunk
+    length: u64,
+    /// index of the first pattern of the chunk
+    pub(crate) first_pattern: u64,
+    pub(crate) patterns: u64,
+}
+
+/// Locations of the chunks, stored at the end of the file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChunkIndex {
+    /// the metadata and the key value pairs to preload
+    header: Chunk,
+    /// the patterns, in order
+    chunks: Vec<Chunk>,
+}
+
+fn encode_record<T: Serialize>(buf: &mut Vec<u8>, record: &T) {
+    let encoded = bincode::serialize(record).unwrap();
+    buf.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&encoded);
+}
+
+fn invalid_data(message: impl Display) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Length prefixed, bincode encoded records of a decompressed chunk.
+struct Records<'a>(&'a [u8]);
+
+impl Records<'_> {
+    fn next_bytes(&mut self) -> IoResult<Option<&[u8]>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let truncated = || invalid_data("truncated record in data file");
+        let (length, rest) = self.0.split_at_checked(8).ok_or_else(truncated)?;
+        let length = u64::from_le_bytes(length.try_into().unwrap()) as usize;
+        let (record, rest) = rest.split_at_checked(length).ok_or_else(truncated)?;
+        self.0 = rest;
+        Ok(Some(record))
+    }
+
+    fn next<T: DeserializeOwned>(&mut self) -> IoResult<Option<T>> {
+        self.next_bytes()?
+            .map(|record| bincode::deserialize(record).map_err(invalid_data))
+            .transpose()
+    }
+}
+
+/// Writes patterns in the format `DataFile` reads: independently zstd
+/// compressed chunks of length prefixed, bincode encoded records, followed by
+/// an index of the chunks. The first chunk holds the metadata and the key
+/// value pairs to preload, every further one up to `CHUNK_PATTERNS` patterns.
+pub(crate) struct PatternWriter<W: Write> {
+    out: BufWriter<W>,
+    compression_level: i32,
+    /// uncompressed records of the chunk being filled
+    chunk: Vec<u8>,
+    chunk_patterns: u64,
+    /// bytes written to `out` so far
+    position: u64,
+    patterns: u64,
+    index: ChunkIndex,
+    header_written: bool,
+}
+
+impl<W: Write> PatternWriter<W> {
+    pub(crate) fn new(
+        out: W,
+        compression_level: i32,
+        metadata: &DataFileMetadata,
+    ) -> IoResult<Self> {
+        let mut ret = Self {
+            out: BufWriter::new(out),
+            compression_level,
+            chunk: Vec::new(),
+            chunk_patterns: 0,
+            position: 0,
+            patterns: 0,
+            index: ChunkIndex::default(),
+            header_written: false,
+        };
+        encode_record(&mut ret.chunk, metadata);
+        Ok(ret)
+    }
+
+    pub(crate) fn write_preload(&mut self, key: &str, value: &str) -> IoResult<()> {
+        if self.header_written {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "key value pairs to preload have to be written before the patterns",
+            ));
+        }
+        encode_record(&mut self.chunk, &(key, value));
+        Ok(())
+    }
+
+    pub(crate) fn write_pattern(&mut self, pattern: &ExecPattern) -> IoResult<()> {
+        if !self.header_written {
+            self.index.header = self.write_chunk()?;
+            self.header_written = true;
+        }
+        encode_record(&mut self.chunk, pattern);
+        self.chunk_patterns += 1;
+        self.patterns += 1;
+        if self.chunk_patterns == CHUNK_PATTERNS {
+            let chunk = self.write_chunk()?;
+            self.index.chunks.push(chunk);
+        }
+        Ok(())
+    }
+
+    /// Compresses and writes the records collected so far as one chunk.
+    fn write_chunk(&mut self) -> IoResult<Chunk> {
+        let compressed = zstd::bulk::compress(&self.chunk, self.compression_level)?;
+        self.out.write_all(&compressed)?;
+        let chunk = Chunk {
+            offset: self.position,
+            length: compressed.len() as u64,
+            first_pattern: self.patterns - self.chunk_patterns,
+            patterns: self.chunk_patterns,
+        };
+        self.position += chunk.length;
+        self.chunk.clear();
+        self.chunk_patterns = 0;
+        Ok(chunk)
+    }
+
+    /// Writes the last chunk and the index, flushes all buffers and hands
+    /// back the underlying writer.
+    pub(crate) fn finish(mut self) -> IoResult<W> {
+        if !self.header_written {
+            self.index.header = self.write_chunk()?;
+        }
+        if self.chunk_patterns > 0 {
+            let chunk = self.write_chunk()?;
+            self.index.chunks.push(chunk);
+        }
+        let index = bincode::serialize(&self.index).map_err(invalid_data)?;
+        self.out.write_all(&index)?;
+        self.out.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.out.write_all(INDEX_MAGIC)?;
+        let mut out = self.out.into_inner()?;
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+/// Checks the version of the metadata record, since the rest of the metadata
+/// may have a different layout in other versions.
+fn read_metadata(records: &mut Records) -> IoResult<DataFileMetadata> {
+    let invalid = |e: &dyn Display| {
+        invalid_data(format!(
+            "couldn't read data file metadata, the file might be outdated => {}",
+            e
+        ))
+    };
+    let record = records
+        .next_bytes()
+        .map_err(|e| invalid(&e))?
+        .ok_or_else(|| invalid(&"the file is empty"))?;
+
+    // the version is the first field
+    let version: u32 = bincode::deserialize(record).map_err(|e| invalid(&e))?;
+    if version != DATA_FILE_VERSION {
+        return Err(invalid_data(format!(
+            "data file version {} is not supported (expected {}), regenerate the file",
+            version, DATA_FILE_VERSION
+        )));
+    }
+    bincode::deserialize(record).map_err(|e| invalid(&e))
+}
+
+/// A data file opened for random access to its chunks. Cloning it is cheap,
+/// every read opens the file anew, so clones can read concurrently.
+#[derive(Debug, Clone)]
+pub(crate) struct DataFile {
+    path: PathBuf,
+    index: Arc<ChunkIndex>,
+    pub(crate) metadata: DataFileMetadata,
+}
+
+impl DataFile {
+    /// Reads the chunk index and the metadata.
+    pub(crate) async fn open<P: AsRef<Path>>(path: P) -> IoResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = tokio::fs::File::open(&path).await?;
+        let length = file.metadata().await?.len();
+
+        let mut footer = [0u8; 16];
+        let outdated = || {
+            invalid_data(format!(
+                "{:?} has no chunk index, the file might be outdated, regenerate it",
+                path
+            ))
+        };
+        if length < footer.len() as u64 {
+            return Err(outdated());
+        }
+        file.seek(SeekFrom::Start(length - footer.len() as u64))
+            .await?;
+        file.read_exact(&mut footer).await?;
+        if &footer[8..] != INDEX_MAGIC {
+            return Err(outdated());
+        }
+        let index_length = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_start = (length - footer.len() as u64)
+            .checked_sub(index_length)
+            .ok_or_else(outdated)?;
+        file.seek(SeekFrom::Start(index_start)).await?;
+        let mut index = vec![0u8; index_length as usize];
+        file.read_exact(&mut index).await?;
+        let index: ChunkIndex = bincode::deserialize(&index).map_err(invalid_data)?;
+
+        let header = read_chunk_bytes(&path, index.header).await?;
+        let metadata = read_metadata(&mut Records(&header))?;
+        Ok(Self {
+            path,
+            index: Arc::new(index),
+            metadata,
+        })
+    }
+
+    /// Number of patterns in the file.
+    pub(crate) fn patterns(&self) -> u64 {
+        self.index
+            .chunks
+            .last()
+            .map_or(0, |c| c.first_pattern + c.patterns)
+    }
+
+    pub(crate) fn chunks(&self) -> &[Chunk] {
+        &self.index.chunks
+    }
+
+    /// Index of the chunk holding the pattern at `position`.
+    pub(crate) fn chunk_of(&self, position: u64) -> usize {
+        self.index
+            .chunks
+            .partition_point(|c| c.first_pattern + c.patterns <= position)
+    }
+
+    /// Reads the key value pairs to preload.
+    pub(crate) async fn read_preload(&self) -> IoResult<Vec<(String, String)>> {
+        let header = read_chunk_bytes(&self.path, self.index.header).await?;
+        let mut records = Records(&header);
+        read_metadata(&mut records)?;
+        let mut entries = Vec::with_capacity(self.metadata.preload as usize);
+        while let Some(entry) = records.next()? {
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Reads and decodes the patterns of a chunk, decompressing and decoding
+    /// them on the blocking thread pool, so several chunks can be decoded in
+    /// parallel.
+    pub(crate) async fn read_chunk(&self, chunk: usize) -> IoResult<Vec<ExecPattern>> {
+        let compressed = read_compressed(&self.path, self.index.chunks[chunk]).await?;
+        tokio::task::spawn_blocking(move || {
+            let decompressed = zstd::decode_all(compressed.as_slice())?;
+            let mut records = Records(&decompressed);
+            let mut patterns = Vec::new();
+            while let Some(pattern) = records.next()? {
+                patterns.push(pattern);
+            }
+            Ok(patterns)
+        })
+        .await?
+    }
+
+    /// Reads the patterns in `range`, decoding all chunks in parallel.
+    pub(crate) async fn read_patterns(&self, range: Range<u64>) -> IoResult<Vec<ExecPattern>> {
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        let chunks = self.chunk_of(range.start)..self.chunk_of(range.end - 1) + 1;
+        let handles: Vec<_> = chunks
+            .map(|chunk| {
+                let data_file = self.clone();
+                let first = self.index.chunks[chunk].first_pattern;
+                (
+                    first,
+                    tokio::spawn(async move { data_file.read_chunk(chunk).await }),
+                )
+            })
+            .collect();
+        let mut patterns = Vec::with_capacity((range.end - range.start) as usize);
+        for (first, handle) in handles {
+            patterns.extend(
+                (first..)
+                    .zip(handle.await??)
+                    .filter(|(position, _)| range.contains(position))
+                    .map(|(_, pattern)| pattern),
+            );
+        }
+        Ok(patterns)
+    }
+}
+
+async fn read_compressed(path: &Path, chunk: Chunk) -> IoResult<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(chunk.offset)).await?;
+    let mut compressed = vec![0u8; chunk.length as usize];
+    file.read_exact(&mut compressed).await?;
+    Ok(compressed)
+}
+
+async fn read_chunk_bytes(path: &Path, chunk: Chunk) -> IoResult<Vec<u8>> {
+    zstd::decode_all(read_compressed(path, chunk).await?.as_slice())
+}
+
+/// Part of the patterns of a data file, given by pattern positions as
+/// `start..end`, `start..` or `..end`, so several clients can replay
+/// disjoint parts of the same file. The predicted responses assume every
+/// pattern before them ran, so they only hold for ranges starting at 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PatternRange {
+    pub start: u64,
+    /// up to the end of the file if `None`
+    pub end: Option<u64>,
+}
+
+impl PatternRange {
+    /// The positions of the range in a file of `patterns` patterns.
+    pub(crate) fn resolve(&self, patterns: u64) -> Result<Range<u64>, String> {
+        let end = self.end.map_or(patterns, |end| end.min(patterns));
+        if self.start >= end {
+            return Err(format!(
+                "pattern range {} holds no patterns of the {} in the data file",
+                self, patterns
+            ));
+        }
+        Ok(self.start..end)
+    }
+}
+
+impl FromStr for PatternRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once("..")
+            .ok_or_else(|| format!("expected a range like 0..1000, found {:?}", s))?;
+        let parse = |position: &str| {
+            position
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid pattern position {:?} => {}", position, e))
+        };
+        let start = if start.trim().is_empty() {
+            0
+        } else {
+            parse(start)?
+        };
+        let end = if end.trim().is_empty() {
+            None
+        } else {
+            Some(parse(end)?)
+        };
+        if end.is_some_and(|end| end <= start) {
+            return Err(format!("empty pattern range {:?}", s));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Display for PatternRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}..{}", self.start, end),
+            None => write!(f, "{}..", self.start),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_chunked_data_file_random_access() {
+    use crate::pattern::basic::{BasicCommand, BasicPattern};
+
+    let path = std::env::temp_dir().join(format!("chunked-test-{}.bin", std::process::id()));
+    let metadata = DataFileMetadata {
+        preload: 1,
+        ..DataFileMetadata::default()
+    };
+    let file = std::fs::File::create(&path).unwrap();
+    let mut writer = PatternWriter::new(file, 0, &metadata).unwrap();
+    writer.write_preload("preloaded", "value").unwrap();
+    let total = 2 * CHUNK_PATTERNS + 10;
+    for position in 0..total {
+        let get = BasicCommand::Get {
+            key: position.to_string(),
+        };
+        writer
+            .write_pattern(&BasicPattern(vec![get], vec!["not found".into()], None))
+            .unwrap();
+    }
+    assert!(writer.write_preload("late", "value").is_err());
+    writer.finish().unwrap();
+
+    let data_file = DataFile::open(&path).await.unwrap();
+    assert_eq!(data_file.metadata.preload, 1);
+    assert_eq!(data_file.patterns(), total);
+    assert_eq!(data_file.chunks().len(), 3);
+    assert_eq!(data_file.chunk_of(CHUNK_PATTERNS), 1);
+    assert_eq!(
+        data_file.read_preload().await.unwrap(),
+        [("preloaded".to_string(), "value".to_string())]
+    );
+
+    let range = "4090..4100".parse::<PatternRange>().unwrap();
+    let patterns = data_file
+        .read_patterns(range.resolve(total).unwrap())
+        .await
+        .unwrap();
+    let keys: Vec<&str> = patterns.iter().map(|p| p.0[0].key()).collect();
+    let expected: Vec<String> = (4090..4100).map(|p: u64| p.to_string()).collect();
+    assert_eq!(keys, expected);
+    assert!("10..".parse::<PatternRange>().unwrap().resolve(5).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
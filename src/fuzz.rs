@@ -0,0 +1,518 @@
+use std::future::Future;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+
+use crate::connection::Connector;
+use crate::generator::generate_valid_string;
+use crate::worker::connect;
+
+/// Time the server gets to answer the health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time the server gets to become healthy after the restart command.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time the server gets to accept the connection and to take each write of
+/// an input.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time responses to an input are drained before the connection is dropped.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(50);
+/// Upper bound on the number of replays while minimizing an input.
+const MAX_MINIMIZE_ATTEMPTS: usize = 500;
+const HUGE_LINE: usize = 4 << 20;
+
+/// Something the fuzzer does on a connection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Action {
+    Write(Vec<u8>),
+    Pause(Duration),
+    /// drop the connection without shutting it down
+    Close,
+}
+
+/// Everything sent to the server on a single connection.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(crate) struct FuzzInput(pub(crate) Vec<Action>);
+
+impl FuzzInput {
+    /// All bytes the input writes, in order.
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .filter_map(|action| match action {
+                Action::Write(bytes) => Some(bytes.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Writes the actions to `path`, including the pauses and closes, so the
+    /// input can be replayed as it was sent.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        std::fs::write(path, bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let bytes = std::fs::read(path)?;
+        bincode::deserialize(&bytes)
+            .map_err(|e| format!("{:?} is not an input saved by fuzz => {}", path, e).into())
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        self.0
+            .iter()
+            .map(|action| match action {
+                Action::Write(bytes) if bytes.len() > 80 => {
+                    format!("write {} bytes", bytes.len())
+                }
+                Action::Write(bytes) => format!("write {:?}", String::from_utf8_lossy(bytes)),
+                Action::Pause(pause) => format!("pause {:?}", pause),
+                Action::Close => "close".to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn valid_command<R: Rng>(rng: &mut R) -> Vec<u8> {
+    let key = generate_valid_string(rng.gen_range(1..=10));
+    match rng.gen_range(0..3) {
+        0 => format!("GET {}\n", key),
+        1 => format!(
+            "SET {} {}\n",
+            key,
+            generate_valid_string(rng.gen_range(1..=20))
+        ),
+        _ => format!("DEL {}\n", key),
+    }
+    .into_bytes()
+}
+
+fn malformed_command<R: Rng>(rng: &mut R) -> Vec<u8> {
+    let mut ret = match rng.gen_range(0..10) {
+        0 => b"PUT a b\n".to_vec(),
+        1 => b"get a\n".to_vec(),
+        2 => b"GET\n".to_vec(),
+        3 => b"GET \n".to_vec(),
+        4 => b"SET a\n".to_vec(),
+        5 => b"GET a b c\n".to_vec(),
+        6 => b"\n\n\n".to_vec(),
+        7 => b"GET a\r\n".to_vec(),
+        8 => vec![b'a'; HUGE_LINE],
+        _ => (0..rng.gen_range(1..256)).map(|_| rng.gen()).collect(),
+    };
+    if rng.gen_bool(0.2) {
+        let idx = rng.gen_range(0..=ret.len());
+        ret.insert(idx, 0);
+    }
+    ret
+}
+
+fn mutate<R: Rng>(bytes: &mut Vec<u8>, rng: &mut R) {
+    if bytes.is_empty() {
+        bytes.push(rng.gen());
+        return;
+    }
+    let idx = rng.gen_range(0..bytes.len());
+    match rng.gen_range(0..6) {
+        0 => bytes[idx] ^= 1 << rng.gen_range(0..8),
+        1 => bytes.insert(idx, rng.gen()),
+        2 => {
+            bytes.remove(idx);
+        }
+        3 => bytes[idx] = 0,
+        4 => {
+            // missing newline
+            bytes.retain(|b| *b != b'\n');
+        }
+        _ => {
+            let end = rng.gen_range(idx..bytes.len()) + 1;
+            let duplicate = bytes[idx..end].to_vec();
+            bytes.splice(idx..idx, duplicate);
+        }
+    }
+}
+
+/// Draws a random input, mixing valid, malformed and mutated commands,
+/// written at once or in fragments, optionally ending with an abrupt close.
+pub(crate) fn generate_input<R: Rng>(rng: &mut R) -> FuzzInput {
+    let mut actions = Vec::new();
+    for _ in 0..rng.gen_range(1..=8) {
+        let mut bytes = match rng.gen_range(0..3) {
+            0 => valid_command(rng),
+            1 => malformed_command(rng),
+            _ => {
+                let mut bytes = valid_command(rng);
+                for _ in 0..rng.gen_range(1..=4) {
+                    mutate(&mut bytes, rng);
+                }
+                bytes
+            }
+        };
+
+        if rng.gen_bool(0.2) && bytes.len() > 1 {
+            // partial writes
+            while bytes.len() > 1 {
+                let rest = bytes.split_off(rng.gen_range(1..bytes.len()));
+                actions.push(Action::Write(bytes));
+                actions.push(Action::Pause(Duration::from_millis(rng.gen_range(1..20))));
+                bytes = rest;
+            }
+        }
+        actions.push(Action::Write(bytes));
+    }
+    if rng.gen_bool(0.2) {
+        actions.push(Action::Close);
+    }
+    FuzzInput(actions)
+}
+
+/// Sends the input on a new connection, draining whatever the server
+/// answers. I/O errors are expected, the server may close the connection
+/// on invalid input. Returns an error if connecting or a write didn't finish
+/// within `limit`, a server that stops accepting or reading hangs.
+async fn send(connector: &Connector, input: &FuzzInput, limit: Duration) -> Result<(), String> {
+    let (stream, _) =
+        match tokio::time::timeout(limit, connect(connector, &connector.targets[0])).await {
+            Ok(Ok(connection)) => connection,
+            // the health check tells whether the server is gone
+            Ok(Err(_)) => return Ok(()),
+            Err(_) => return Err(format!("connecting took longer than {:?}", limit)),
+        };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut drain = tokio::spawn(async move {
+        let mut buf = vec![0u8; 4096];
+        while reader.read(&mut buf).await.unwrap_or(0) > 0 {}
+    });
+
+    for action in input.0.iter() {
+        match action {
+            Action::Write(bytes) => {
+                let write = async {
+                    writer.write_all(bytes).await?;
+                    writer.flush().await
+                };
+                match tokio::time::timeout(limit, write).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => break,
+                    Err(_) => {
+                        drain.abort();
+                        return Err(format!(
+                            "the server stopped reading, writing {} bytes took longer than {:?}",
+                            bytes.len(),
+                            limit
+                        ));
+                    }
+                }
+            }
+            Action::Pause(pause) => tokio::time::sleep(*pause).await,
+            Action::Close => {
+                drain.abort();
+                return Ok(());
+            }
+        }
+    }
+
+    let _ = tokio::time::timeout(DRAIN_TIMEOUT, &mut drain).await;
+    drain.abort();
+    let _ = tokio::time::timeout(limit, writer.shutdown()).await;
+    Ok(())
+}
+
+/// Checks that the server still accepts connections and answers a `SET`
+/// followed by a `GET` of the same key.
+async fn health_check(connector: &Connector) -> Result<(), String> {
+    let key = format!("fuzzhealth{}", generate_valid_string(8));
+    let value = generate_valid_string(8);
+    let check = async {
+        let (stream, _) = connect(connector, &connector.targets[0])
+            .await
+            .map_err(|e| format!("couldn't connect => {}", e))?;
+        let mut conn = BufStream::new(stream);
+        let mut response = String::new();
+        for command in [format!("SET {} {}\n", key, value), format!("GET {}\n", key)] {
+            response.clear();
+            conn.write_all(command.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+            conn.flush().await.map_err(|e| e.to_string())?;
+            if conn
+                .read_line(&mut response)
+                .await
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("connection closed during the health check".to_string());
+            }
+        }
+        if response.trim_end_matches('\n') != value {
+            return Err(format!(
+                "GET after SET answered {:?} instead of {:?}",
+                response, value
+            ));
+        }
+        let _ = conn.write_all(format!("DEL {}\n", key).as_bytes()).await;
+        let _ = conn.into_inner().shutdown().await;
+        Ok(())
+    };
+    tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err(format!("no response within {:?}", HEALTH_CHECK_TIMEOUT)))
+}
+
+/// Runs the restart command and waits until the server passes the health
+/// check again.
+async fn restart(connector: &Connector, command: &str) -> Result<(), String> {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(|e| format!("couldn't run the restart command => {}", e))?;
+    if !status.success() {
+        return Err(format!("the restart command failed with {}", status));
+    }
+
+    let start = tokio::time::Instant::now();
+    loop {
+        match health_check(connector).await {
+            Ok(()) => return Ok(()),
+            Err(e) if start.elapsed() > RESTART_TIMEOUT => {
+                return Err(format!("server didn't recover after the restart => {}", e))
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+/// Shrinks the input as long as it still reproduces the failure, first by
+/// dropping whole actions, then by cutting ever smaller ranges out of the
+/// written bytes.
+pub(crate) async fn minimize<F, Fut>(input: FuzzInput, mut reproduces: F) -> FuzzInput
+where
+    F: FnMut(FuzzInput) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let mut current = input;
+    let mut attempts = 0;
+
+    for idx in (0..current.0.len()).rev() {
+        if attempts >= MAX_MINIMIZE_ATTEMPTS || current.0.len() <= 1 {
+            break;
+        }
+        let mut candidate = current.clone();
+        candidate.0.remove(idx);
+        attempts += 1;
+        if reproduces(candidate.clone()).await {
+            current = candidate;
+        }
+    }
+
+    for idx in 0..current.0.len() {
+        let len = match &current.0[idx] {
+            Action::Write(bytes) => bytes.len(),
+            _ => continue,
+        };
+        let mut chunk = len.div_ceil(2);
+        while chunk > 0 {
+            let mut start = 0;
+            while start < current_len(&current, idx) {
+                if attempts >= MAX_MINIMIZE_ATTEMPTS {
+                    return current;
+                }
+                let mut candidate = current.clone();
+                if let Action::Write(bytes) = &mut candidate.0[idx] {
+                    let end = (start + chunk).min(bytes.len());
+                    bytes.drain(start..end);
+                    if bytes.is_empty() {
+                        break;
+                    }
+                }
+                attempts += 1;
+                if reproduces(candidate.clone()).await {
+                    current = candidate;
+                } else {
+                    start += chunk;
+                }
+            }
+            chunk /= 2;
+        }
+    }
+
+    current
+}
+
+fn current_len(input: &FuzzInput, idx: usize) -> usize {
+    match &input.0[idx] {
+        Action::Write(bytes) => bytes.len(),
+        _ => 0,
+    }
+}
+
+/// Sends random inputs to the server and checks its health after each of
+/// them. The input after which the server failed is minimized, if a restart
+/// command is given, and saved to `out` for [`replay_fuzz_input`]. Returns
+/// whether the server survived all inputs.
+pub async fn fuzz(
+    connector: Connector,
+    iterations: usize,
+    seed: Option<u64>,
+    restart_command: Option<String>,
+    out: &Path,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let seed = seed.unwrap_or_else(rand::random);
+    println!("fuzzing with seed {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    if let Err(e) = health_check(&connector).await {
+        return Err(format!("server is unhealthy before fuzzing => {}", e).into());
+    }
+
+    let bar = indicatif::ProgressBar::new(iterations as u64);
+    for iteration in 0..iterations {
+        let input = generate_input(&mut rng);
+        let sent = send(&connector, &input, SEND_TIMEOUT).await;
+        bar.inc(1);
+
+        let reason = match sent {
+            Err(hang) => hang,
+            Ok(()) => match health_check(&connector).await {
+                Ok(()) => continue,
+                Err(reason) => reason,
+            },
+        };
+        bar.finish_and_clear();
+        println!("server failed after input {} => {}", iteration, reason);
+
+        let minimal = match &restart_command {
+            None => {
+                println!("pass a restart command to minimize the input");
+                input
+            }
+            Some(command) => {
+                restart(&connector, command).await?;
+                let connector = &connector;
+                let reproduces = |candidate: FuzzInput| async move {
+                    let failed = send(connector, &candidate, SEND_TIMEOUT).await.is_err()
+                        || health_check(connector).await.is_err();
+                    if failed {
+                        if let Err(e) = restart(connector, command).await {
+                            println!("{}", e);
+                        }
+                    }
+                    failed
+                };
+                if reproduces(input.clone()).await {
+                    let minimal = minimize(input, reproduces).await;
+                    println!("minimized the input to {} bytes", minimal.bytes().len());
+                    minimal
+                } else {
+                    println!("the input alone doesn't reproduce the failure, saving it as is");
+                    input
+                }
+            }
+        };
+
+        minimal.save(out)?;
+        println!("{}", minimal.describe());
+        println!("saved the input to {:?}", out);
+        return Ok(false);
+    }
+    bar.finish_and_clear();
+
+    println!("the server survived {} inputs", iterations);
+    Ok(true)
+}
+
+/// Sends an input saved by [`fuzz`] and checks the health of the server
+/// after it. Returns whether the server survived.
+pub async fn replay_fuzz_input(
+    connector: Connector,
+    input: &Path,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let input = FuzzInput::load(input)?;
+    println!("{}", input.describe());
+    let health = match send(&connector, &input, SEND_TIMEOUT).await {
+        Ok(()) => health_check(&connector).await,
+        Err(hang) => Err(hang),
+    };
+    match health {
+        Ok(()) => {
+            println!("the server survived the input");
+            Ok(true)
+        }
+        Err(reason) => {
+            println!("server failed after the input => {}", reason);
+            Ok(false)
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_minimize_keeps_the_trigger() {
+    let input = FuzzInput(vec![
+        Action::Write(b"GET a\n".to_vec()),
+        Action::Write(b"SET a\0b\n".to_vec()),
+        Action::Pause(Duration::from_millis(1)),
+        Action::Write(b"DEL a\n".to_vec()),
+        Action::Close,
+    ]);
+    let minimal = minimize(
+        input,
+        |candidate| async move { candidate.bytes().contains(&0) },
+    )
+    .await;
+    assert_eq!(minimal, FuzzInput(vec![Action::Write(vec![0])]));
+
+    // pauses and closes survive saving, partial writes stay separate
+    let saved = FuzzInput(vec![
+        Action::Write(b"SE".to_vec()),
+        Action::Pause(Duration::from_millis(3)),
+        Action::Write(b"T a b\n".to_vec()),
+        Action::Close,
+    ]);
+    let path = std::env::temp_dir().join(format!("fuzz-input-{}.bin", std::process::id()));
+    saved.save(&path).unwrap();
+    assert_eq!(FuzzInput::load(&path).unwrap(), saved);
+    std::fs::write(&path, b"GET a\n").unwrap();
+    assert!(FuzzInput::load(&path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn test_send_reports_a_server_that_stops_reading() {
+    use crate::connection::Target;
+    use crate::options::TlsArgs;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.push(stream);
+        }
+    });
+    let args = TlsArgs {
+        tls: false,
+        tls_ca: None,
+        tls_cert: None,
+        tls_key: None,
+        tls_sni: None,
+    };
+    let connector = Connector::new(vec![Target::Tcp(address)], &args).unwrap();
+
+    let limit = Duration::from_millis(200);
+    let small = FuzzInput(vec![Action::Write(b"GET a\n".to_vec())]);
+    assert_eq!(send(&connector, &small, limit).await, Ok(()));
+    let huge = FuzzInput(vec![Action::Write(vec![b'a'; 16 * HUGE_LINE])]);
+    let hang = send(&connector, &huge, limit).await.unwrap_err();
+    assert!(hang.contains("stopped reading"), "{}", hang);
+}
@@ -1,15 +1,17 @@
-use std::io::{Result as IoResult, Write};
+use std::io::Result as IoResult;
 use std::path::PathBuf;
 
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use rand::{thread_rng, Rng};
+use rand::{seq::SliceRandom, thread_rng, Rng};
 use std::fs::File;
-use std::io::BufWriter;
 use std::ops::RangeInclusive;
-use zstd::Encoder;
 
+use crate::datafile::{DataFileMetadata, PatternWriter};
 use crate::pattern::basic::{BasicPattern, BasicState};
-use crate::pattern::ParsePattern;
+use crate::pattern::PatternMix;
+use crate::routing::{stable_hash, Routing};
+use crate::think_time::ThinkTimes;
+use crate::value_size::ValueSize;
 
 const LOWER_CASE_CHARS: RangeInclusive<char> = 'a'..='z';
 const UPPER_CASE_CHARS: RangeInclusive<char> = 'A'..='Z';
@@ -24,10 +26,7 @@ lazy_static::lazy_static! {
 
 #[allow(unused)]
 fn is_char_valid(inp: char) -> bool {
-    match inp {
-        'a'..='z' | 'A'..='Z' => true,
-        _ => false,
-    }
+    inp.is_ascii_alphabetic()
 }
 
 #[inline(always)]
@@ -47,6 +46,120 @@ pub(crate) fn generate_valid_string(len: usize) -> String {
     ret
 }
 
+/// One of several disjoint parts of the key space. Keys are assigned to
+/// partitions by their hash, so the assignment doesn't depend on the order in
+/// which they were generated.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Partition {
+    pub(crate) index: u32,
+    pub(crate) count: u32,
+}
+
+impl Partition {
+    #[inline]
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        stable_hash(key.as_bytes()) % u64::from(self.count) == u64::from(self.index)
+    }
+}
+
+/// Attempts per partition at generating a key in a given partition, a key
+/// lands in it with a chance of one in the number of partitions.
+const KEY_ATTEMPTS_PER_PARTITION: u32 = 64;
+
+/// Generates a random key, restricted to `partition` if given. Fails if no
+/// key of `len` characters is found in the partition, which happens when
+/// there are too few keys of that length to cover every partition.
+pub(crate) fn generate_key(len: usize, partition: Option<Partition>) -> Result<String, String> {
+    let attempts = partition.map_or(1, |p| p.count.saturating_mul(KEY_ATTEMPTS_PER_PARTITION));
+    for _ in 0..attempts {
+        let key = generate_valid_string(len);
+        if partition.is_none_or(|p| p.contains(&key)) {
+            return Ok(key);
+        }
+    }
+    let partition = partition.unwrap();
+    Err(format!(
+        "found no key of {} characters in partition {} of {}, use longer keys or fewer partitions",
+        len, partition.index, partition.count
+    ))
+}
+
+/// Attempts at drawing a key to preload that wasn't drawn before.
+const UNUSED_KEY_ATTEMPTS: u32 = 1000;
+
+/// Approximate number of distinct keys of `len` characters in each of
+/// `partitions` partitions.
+fn keys_per_partition(len: usize, partitions: u32) -> u64 {
+    let keys = u32::try_from(len)
+        .ok()
+        .and_then(|len| (ASCII_CHARS.len() as u64).checked_pow(len))
+        .unwrap_or(u64::MAX);
+    keys / u64::from(partitions.max(1))
+}
+
+/// Parses a key size, keys have at least one character.
+pub(crate) fn parse_key_size(s: &str) -> Result<usize, String> {
+    let size: usize = s.parse().map_err(|e| format!("{}", e))?;
+    if size == 0 {
+        return Err("keys need at least one character".to_string());
+    }
+    Ok(size)
+}
+
+/// Keys set by the preload phase, grouped by partition. Patterns that only
+/// read draw their keys from here, so they hit existing values. The keys are
+/// never written, so their predictions hold on every pass over the file.
+#[derive(Debug, Default)]
+pub(crate) struct PreloadedKeys(Vec<Vec<String>>);
+
+impl PreloadedKeys {
+    pub(crate) fn choose<R: Rng>(&self, partition: Option<Partition>, rng: &mut R) -> Option<&str> {
+        let idx = partition.map_or(0, |p| p.index as usize);
+        self.0
+            .get(idx)
+            .and_then(|keys| keys.choose(rng))
+            .map(String::as_str)
+    }
+}
+
+#[test]
+fn test_generate_key_in_partition() {
+    let partition = Partition { index: 2, count: 5 };
+    for _ in 0..100 {
+        let key = generate_key(10, Some(partition)).unwrap();
+        assert!(partition.contains(&key));
+        assert!(!Partition { index: 3, count: 5 }.contains(&key));
+    }
+
+    // the only key of no characters lands in one of the partitions
+    let empty = (0..5).find(|&index| Partition { index, count: 5 }.contains(""));
+    let other = (empty.unwrap() + 1) % 5;
+    assert!(generate_key(
+        0,
+        Some(Partition {
+            index: other,
+            count: 5
+        })
+    )
+    .is_err());
+    assert_eq!(parse_key_size("3"), Ok(3));
+    assert!(parse_key_size("0").is_err());
+}
+
+#[tokio::test]
+async fn test_preload_beyond_the_key_space_is_rejected() {
+    assert_eq!(keys_per_partition(2, 4), 52 * 52 / 4);
+    assert_eq!(keys_per_partition(100, 4), u64::MAX / 4);
+
+    let data_out = std::env::temp_dir().join(format!("preload-{}.bin", std::process::id()));
+    let config = GenerateConfig::new(&data_out)
+        .key_size(1)
+        .partitions(2)
+        .preload(1000);
+    assert!(config.generate().await.is_err());
+    assert!(!data_out.exists());
+}
+
 #[test]
 fn test_generate_valid_ascii_char() {
     let sample_size = ASCII_CHARS.len() * 1_000;
@@ -56,14 +169,140 @@ fn test_generate_valid_ascii_char() {
     }
 }
 
-pub(crate) async fn generate(
-    size: usize,
-    data_out: PathBuf,
-    pattern: ParsePattern,
-    key_size: usize,
-    value_size: usize,
-    compression_level: i32,
-) -> IoResult<()> {
+/// Everything that determines the contents of a generated data file.
+///
+/// Built from [`GenerateConfig::new`], every setter left out keeps the
+/// default of the `generate` subcommand.
+#[derive(Debug, Clone)]
+pub struct GenerateConfig {
+    pub(crate) size: usize,
+    pub(crate) data_out: PathBuf,
+    pub(crate) pattern: PatternMix,
+    pub(crate) key_size: usize,
+    pub(crate) value_size: ValueSize,
+    pub(crate) compression_level: i32,
+    pub(crate) partitions: u32,
+    /// think times stored in the data file
+    pub(crate) think_times: ThinkTimes,
+    /// number of keys set before the benchmark starts
+    pub(crate) preload: usize,
+    /// with hash routing every pattern is kept on a single key
+    pub(crate) routing: Routing,
+}
+
+impl GenerateConfig {
+    /// Generates 1000 `SET-GET-GET-DEL` patterns into `data_out`.
+    pub fn new(data_out: impl Into<PathBuf>) -> Self {
+        Self {
+            size: 1000,
+            data_out: data_out.into(),
+            pattern: "SET-GET-GET-DEL".parse().unwrap(),
+            key_size: 10,
+            value_size: ValueSize::default(),
+            compression_level: 0,
+            partitions: 0,
+            think_times: ThinkTimes::default(),
+            preload: 0,
+            routing: Routing::RoundRobin,
+        }
+    }
+
+    /// Number of patterns.
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn pattern(mut self, pattern: PatternMix) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    /// Key size in number of characters.
+    pub fn key_size(mut self, key_size: usize) -> Self {
+        self.key_size = key_size;
+        self
+    }
+
+    pub fn value_size(mut self, value_size: ValueSize) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    /// zstd compression level, from 0 to 21.
+    pub fn compression_level(mut self, compression_level: i32) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    /// Splits the key space into disjoint partitions, see the `generate`
+    /// subcommand.
+    pub fn partitions(mut self, partitions: u32) -> Self {
+        self.partitions = partitions;
+        self
+    }
+
+    pub fn think_times(mut self, think_times: ThinkTimes) -> Self {
+        self.think_times = think_times;
+        self
+    }
+
+    /// Number of keys set before the benchmark starts.
+    pub fn preload(mut self, preload: usize) -> Self {
+        self.preload = preload;
+        self
+    }
+
+    /// Routing the file is generated for, with [`Routing::Hash`] all commands
+    /// of a pattern act on the same key, so the pattern stays on one host.
+    pub fn routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Writes the data file.
+    pub async fn generate(self) -> IoResult<()> {
+        generate(self).await
+    }
+}
+
+pub(crate) async fn generate(config: GenerateConfig) -> IoResult<()> {
+    let GenerateConfig {
+        size,
+        data_out,
+        pattern,
+        key_size,
+        value_size,
+        compression_level,
+        partitions,
+        think_times,
+        preload,
+        routing,
+    } = config;
+
+    if key_size == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "keys need at least one character",
+        ));
+    }
+    if preload > 0 && partitions == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "preloading keys needs partitions, so every key is read by one worker only",
+        ));
+    }
+    let available = keys_per_partition(key_size, partitions);
+    if preload.div_ceil(partitions.max(1) as usize) as u64 > available {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "can't preload {} distinct keys of {} characters, each of the {} partitions only holds about {}",
+                preload, key_size, partitions, available
+            ),
+        ));
+    }
+
     let multi = MultiProgress::new();
 
     let bytes_style = ProgressStyle::default_spinner()
@@ -80,39 +319,81 @@ pub(crate) async fn generate(
 
     let file = File::create(data_out)?;
     let file_bar = bytes_bar.wrap_write(file);
-    let buffered = BufWriter::new(file_bar);
-    let compressor = Encoder::new(buffered, compression_level)?;
-    let mut buf_comp = BufWriter::new(compressor);
+    let value_sizes = value_size.sampler().map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid value size {} => {}", value_size, e),
+        )
+    })?;
+    let metadata = DataFileMetadata {
+        partitions,
+        think_times,
+        value_size: Some(value_size),
+        preload: preload as u64,
+        ..Default::default()
+    };
+    let mut writer = PatternWriter::new(file_bar, compression_level, &metadata)?;
 
     bytes_bar.println("created file");
 
     let mut state = BasicState::new();
+    let mut rng = thread_rng();
 
-    for _ in 0..size {
-        let gen_pattern = BasicPattern::new(&pattern, key_size, value_size, &mut state);
+    let mut preloaded = PreloadedKeys(vec![Vec::new(); partitions.max(1) as usize]);
+    for idx in 0..preload {
+        let partition = (partitions > 0).then(|| Partition {
+            index: (idx % partitions as usize) as u32,
+            count: partitions,
+        });
+        let mut key = None;
+        for _ in 0..UNUSED_KEY_ATTEMPTS {
+            let candidate = generate_key(key_size, partition)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+            if !state.contains_key(&candidate) {
+                key = Some(candidate);
+                break;
+            }
+        }
+        let key = key.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "found no unused key of {} characters to preload, use longer keys or preload fewer",
+                    key_size
+                ),
+            )
+        })?;
+        let value = generate_valid_string(value_sizes.sample(&mut rng));
+        writer.write_preload(&key, &value)?;
+        state.insert(key.clone(), value);
+        preloaded.0[partition.map_or(0, |p| p.index as usize)].push(key);
+    }
+    if preload > 0 {
+        bytes_bar.println(format!("generated {} keys to preload", preload));
+    }
 
-        let encoded = bincode::serialize(&gen_pattern).unwrap();
-        let encoded_pattern_len = encoded.len();
-        let m = encoded_pattern_len.to_le_bytes();
-        buf_comp.write_all(&m)?;
-        buf_comp.flush()?;
-        buf_comp.write_all(&encoded)?;
-        buf_comp.flush()?;
+    for idx in 0..size {
+        let partition = (partitions > 0).then(|| Partition {
+            index: (idx % partitions as usize) as u32,
+            count: partitions,
+        });
+        let gen_pattern = BasicPattern::new(
+            pattern.choose(&mut rng),
+            key_size,
+            &value_sizes,
+            partition,
+            Some(&preloaded),
+            routing == Routing::Hash,
+            &mut state,
+        )
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        writer.write_pattern(&gen_pattern)?;
         patterns_bar.inc(1);
     }
 
     patterns_bar.finish_with_message("finished generating all patterns");
-    bytes_bar.println("flushing compressor buffer");
-    buf_comp.flush()?;
-    let mut compressor = buf_comp.into_inner()?;
-    bytes_bar.println("flushing compressor");
-    compressor.flush()?;
-    let mut buffered = compressor.finish()?;
-    bytes_bar.println("flushing file buffer");
-    buffered.flush()?;
-    let mut file = buffered.into_inner()?;
     bytes_bar.println("flushing file");
-    file.flush()?;
+    writer.finish()?;
     bytes_bar.finish_with_message("finished writing");
 
     multi_progress.await??;
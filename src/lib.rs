@@ -0,0 +1,76 @@
+#![allow(clippy::upper_case_acronyms)]
+
+//! Client for testing and benchmarking key-value servers speaking the line
+//! based `GET key`, `SET key value` and `DEL key` protocol.
+//!
+//! Workloads are generated into data files with [`GenerateConfig`], run
+//! against a server with [`TestConfig`] or [`BenchmarkConfig`], and their
+//! results are handed back as [`TestReport`] and [`BenchmarkReport`].
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! use std::time::Duration;
+//! use server_language_client::{BenchmarkConfig, GenerateConfig, Target};
+//!
+//! let target: Target = "127.0.0.1:8080".parse()?;
+//! GenerateConfig::new("data.bin")
+//!     .size(10_000)
+//!     .pattern("GET:80,SET-GET:20".parse()?)
+//!     .generate()
+//!     .await?;
+//! let mut report = BenchmarkConfig::new(vec![target], "data.bin", Duration::from_secs(10))
+//!     .concurrency(16)
+//!     .run()
+//!     .await?;
+//! println!("p99 {:?}", report.hosts[0].1.percentile(0.99));
+//! # Ok(())
+//! # }
+//! ```
+
+pub(crate) mod autotune;
+pub(crate) mod benchmark;
+pub(crate) mod capture;
+pub(crate) mod conformance;
+pub(crate) mod connection;
+pub(crate) mod datafile;
+pub(crate) mod fuzz;
+pub(crate) mod generator;
+pub(crate) mod linearizability;
+pub(crate) mod manifest;
+pub(crate) mod monitor;
+pub(crate) mod namespace;
+pub mod options;
+pub(crate) mod pattern;
+pub(crate) mod proxy;
+pub(crate) mod results;
+pub(crate) mod routing;
+pub(crate) mod scenario;
+pub(crate) mod server_monitor;
+pub(crate) mod supplier;
+pub(crate) mod test;
+pub(crate) mod text;
+pub(crate) mod think_time;
+pub(crate) mod value_size;
+pub(crate) mod worker;
+
+pub use autotune::{AutoTuneReport, Slo, TuneStep};
+pub use benchmark::{BenchmarkConfig, BenchmarkReport};
+pub use capture::capture;
+pub use conformance::run_conformance;
+pub use connection::{Connector, Target};
+pub use datafile::PatternRange;
+pub use fuzz::{fuzz, replay_fuzz_input};
+pub use generator::GenerateConfig;
+pub use options::TlsArgs;
+pub use pattern::basic::BasicCommand;
+pub use pattern::{ExecPattern, ParsePattern, ParsePatternCommand, PatternExecError, PatternMix};
+pub use proxy::{run_proxy, FaultConfig};
+pub use results::{Latencies, Summary};
+pub use routing::Routing;
+pub use scenario::{benchmark_config, ScenarioError};
+pub use supplier::{PatternResponse, TimeResult};
+pub use test::{TestConfig, TestReport};
+pub use text::{export_text, import_text, Predictions};
+pub use think_time::{ThinkTime, ThinkTimes};
+pub use value_size::ValueSize;
+pub use worker::ConnectionMode;
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+use comfy_table::Table;
+use tokio::time::Instant;
+
+use crate::pattern::{basic::BasicCommand, PatternExecError};
+use crate::supplier::PatternResponse;
+
+const NOT_FOUND: &str = "not found";
+/// Number of violating keys whose minimal history is printed.
+const MAX_REPORTED_VIOLATIONS: usize = 10;
+/// Completion time of an operation whose connection failed, it may take
+/// effect at any point after its invocation or not at all.
+const PENDING: u64 = u64::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Get,
+    Set(String),
+    Del,
+}
+
+/// A single command as observed by the client.
+#[derive(Debug, Clone)]
+pub(crate) struct Event {
+    pub(crate) key: String,
+    pub(crate) operation: Operation,
+    /// value the server answered with, `None` if it answered `not found`
+    /// or the operation is pending
+    pub(crate) output: Option<String>,
+    /// nanoseconds since the start of the run
+    pub(crate) invoked: u64,
+    /// nanoseconds since the start of the run, `PENDING` if no response
+    /// arrived
+    pub(crate) completed: u64,
+}
+
+/// Value of the register a key is modelled as.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Register {
+    /// no event has been linearized yet and the initial value is not known,
+    /// so the first event may observe anything
+    Unknown,
+    Known(Option<String>),
+}
+
+impl Event {
+    fn is_pending(&self) -> bool {
+        self.completed == PENDING
+    }
+
+    /// Applies the event to the register holding the value of its key.
+    /// Returns the new value if the output of the event is consistent with
+    /// the register, `None` if it isn't. Pending events have no output to
+    /// check.
+    #[inline]
+    fn apply(&self, register: &Register) -> Option<Register> {
+        if let Register::Known(value) = register {
+            if !self.is_pending() && self.output != *value {
+                return None;
+            }
+        }
+        Some(Register::Known(match &self.operation {
+            Operation::Get => self.output.clone(),
+            Operation::Set(value) => Some(value.clone()),
+            Operation::Del => None,
+        }))
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.operation {
+            Operation::Get => write!(f, "GET {}", self.key)?,
+            Operation::Set(value) => write!(f, "SET {} {}", self.key, value)?,
+            Operation::Del => write!(f, "DEL {}", self.key)?,
+        }
+        if self.is_pending() {
+            return write!(f, " => no response");
+        }
+        write!(f, " => {}", self.output.as_deref().unwrap_or(NOT_FOUND))
+    }
+}
+
+/// A per key history that has no valid linearization, reduced to a subset of
+/// events that still has none, while removing any single event from it would
+/// make it linearizable.
+#[derive(Debug)]
+pub(crate) struct Violation {
+    pub(crate) key: String,
+    pub(crate) events: Vec<Event>,
+}
+
+/// All operations of a run, recorded with the interval they were in flight.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    events: Vec<Event>,
+    /// values keys held before the first event, all other keys were empty
+    initial: HashMap<String, String>,
+}
+
+impl History {
+    pub(crate) fn from_responses<'a>(
+        responses: impl IntoIterator<Item = &'a PatternResponse>,
+        origin: Instant,
+    ) -> Self {
+        let mut events = Vec::new();
+        for response in responses {
+            let pattern = &response.pattern;
+            let timing = &response.timing;
+            // commands after a failed one were never sent and have no span
+            for (idx, span) in timing.spans.iter().enumerate() {
+                let command = &pattern.0[idx];
+                let observed = match &timing.durations[idx] {
+                    Ok(_) => Some(pattern.1[idx].as_str()),
+                    Err(PatternExecError::InvalidResponse { found, .. }) => Some(found.as_str()),
+                    // a read without a response observed nothing, a write may
+                    // still have been applied by the server
+                    Err(PatternExecError::IoError(_)) => match command {
+                        BasicCommand::Get { .. } => continue,
+                        _ => None,
+                    },
+                };
+                let output = observed
+                    .map(|observed| observed.trim_end_matches('\n'))
+                    .filter(|observed| *observed != NOT_FOUND)
+                    .map(str::to_string);
+                let completed = match observed {
+                    Some(_) => span.completed.duration_since(origin).as_nanos() as u64,
+                    None => PENDING,
+                };
+
+                let operation = match command {
+                    BasicCommand::Get { .. } => Operation::Get,
+                    BasicCommand::Set { value, .. } => Operation::Set(value.clone()),
+                    BasicCommand::Del { .. } => Operation::Del,
+                };
+                events.push(Event {
+                    key: command.key().to_string(),
+                    operation,
+                    output,
+                    invoked: span.invoked.duration_since(origin).as_nanos() as u64,
+                    completed,
+                });
+            }
+        }
+        Self {
+            events,
+            initial: HashMap::new(),
+        }
+    }
+
+    /// Sets the values keys held before the run, e.g. because they were
+    /// preloaded.
+    pub(crate) fn with_initial(mut self, initial: HashMap<String, String>) -> Self {
+        self.initial = initial;
+        self
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Checks the history against a key-value store in which every key is an
+    /// independent register, initially holding its preloaded value or none.
+    /// Keys are checked separately, since linearizability is a local
+    /// property.
+    pub(crate) fn check(&self) -> Vec<Violation> {
+        let mut per_key: HashMap<&str, Vec<&Event>> = HashMap::new();
+        for event in self.events.iter() {
+            per_key.entry(event.key.as_str()).or_default().push(event);
+        }
+
+        let mut violations: Vec<Violation> = per_key
+            .into_iter()
+            .map(|(key, events)| {
+                let known = Register::Known(self.initial.get(key).cloned());
+                (key, events, known)
+            })
+            .filter(|(_, events, known)| !is_linearizable(events, known))
+            .map(|(key, events, known)| {
+                // a violation that holds whatever the initial value was is
+                // reduced without relying on it, otherwise the reduction
+                // would usually end at a single read of a value nobody wrote
+                let initial = if is_linearizable(&events, &Register::Unknown) {
+                    known
+                } else {
+                    Register::Unknown
+                };
+                Violation {
+                    key: key.to_string(),
+                    events: minimize(events, &initial).into_iter().cloned().collect(),
+                }
+            })
+            .collect();
+        violations.sort_by(|a, b| a.key.cmp(&b.key));
+        violations
+    }
+
+    pub(crate) fn keys(&self) -> usize {
+        self.events
+            .iter()
+            .map(|e| e.key.as_str())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+}
+
+/// Wing & Gong style search for a linearization of the events of a single
+/// register. An event may be linearized next if it was invoked before every
+/// remaining event completed. Pending events may be linearized at any point
+/// after their invocation or left out. Visited configurations are cached, so
+/// every combination of linearized events and register value is explored
+/// once.
+fn is_linearizable(events: &[&Event], initial: &Register) -> bool {
+    let mut order: Vec<&Event> = events.to_vec();
+    order.sort_by_key(|e| e.invoked);
+    let words = order.len().div_ceil(64);
+    let required = order.iter().filter(|e| !e.is_pending()).count();
+
+    let mut visited: HashSet<(Vec<u64>, Register)> = HashSet::new();
+    let mut stack = vec![(vec![0u64; words], initial.clone(), 0usize)];
+
+    while let Some((linearized, register, count)) = stack.pop() {
+        if count == required {
+            return true;
+        }
+        if !visited.insert((linearized.clone(), register.clone())) {
+            continue;
+        }
+
+        let is_done = |idx: usize| linearized[idx / 64] & (1 << (idx % 64)) != 0;
+        let min_completed = (0..order.len())
+            .filter(|idx| !is_done(*idx))
+            .map(|idx| order[idx].completed)
+            .min()
+            .unwrap();
+
+        for (idx, event) in order.iter().enumerate() {
+            if event.invoked > min_completed {
+                break;
+            }
+            if is_done(idx) {
+                continue;
+            }
+            if let Some(next) = event.apply(&register) {
+                let mut next_linearized = linearized.clone();
+                next_linearized[idx / 64] |= 1 << (idx % 64);
+                let count = count + usize::from(!event.is_pending());
+                stack.push((next_linearized, next, count));
+            }
+        }
+    }
+
+    false
+}
+
+/// Removes events as long as the remaining history stays non-linearizable.
+/// Later events are dropped first, so the reduction keeps the earliest
+/// anomaly. Dropping a write can turn a removable read into a required one,
+/// so passes are repeated until none removes anything.
+fn minimize<'a>(mut events: Vec<&'a Event>, initial: &Register) -> Vec<&'a Event> {
+    events.sort_by_key(|e| e.invoked);
+    loop {
+        let before = events.len();
+        for idx in (0..events.len()).rev() {
+            let mut candidate = events.clone();
+            candidate.remove(idx);
+            if !is_linearizable(&candidate, initial) {
+                events = candidate;
+            }
+        }
+        if events.len() == before {
+            return events;
+        }
+    }
+}
+
+pub(crate) fn print_report(history: &History, violations: &[Violation]) {
+    if violations.is_empty() {
+        println!(
+            "history of {} operations on {} keys is linearizable",
+            history.len(),
+            history.keys()
+        );
+        return;
+    }
+
+    println!(
+        "history of {} operations on {} keys is NOT linearizable, {} keys violate it",
+        history.len(),
+        history.keys(),
+        violations.len()
+    );
+    for violation in violations.iter().take(MAX_REPORTED_VIOLATIONS) {
+        let mut table = Table::new();
+        table.set_header(vec!["invoked (ns)", "completed (ns)", "operation"]);
+        for event in violation.events.iter() {
+            let completed = if event.is_pending() {
+                "-".to_string()
+            } else {
+                event.completed.to_string()
+            };
+            table.add_row(vec![
+                event.invoked.to_string(),
+                completed,
+                event.to_string(),
+            ]);
+        }
+        println!("minimal non-linearizable history of key {}", violation.key);
+        println!("{table}");
+    }
+    if violations.len() > MAX_REPORTED_VIOLATIONS {
+        println!(
+            "omitted the histories of {} more keys",
+            violations.len() - MAX_REPORTED_VIOLATIONS
+        );
+    }
+}
+
+#[cfg(test)]
+fn event(operation: Operation, output: Option<&str>, invoked: u64, completed: u64) -> Event {
+    Event {
+        key: "k".to_string(),
+        operation,
+        output: output.map(str::to_string),
+        invoked,
+        completed,
+    }
+}
+
+#[test]
+fn test_concurrent_history_is_linearizable() {
+    // the GET overlaps both SETs and may observe either of them
+    let history = History {
+        initial: HashMap::new(),
+        events: vec![
+            event(Operation::Set("a".into()), None, 0, 10),
+            event(Operation::Set("b".into()), Some("a"), 5, 20),
+            event(Operation::Get, Some("a"), 6, 30),
+            event(Operation::Del, Some("b"), 40, 50),
+        ],
+    };
+    assert!(history.check().is_empty());
+}
+
+#[test]
+fn test_stale_read_is_reported_minimal() {
+    let history = History {
+        initial: HashMap::new(),
+        events: vec![
+            event(Operation::Set("a".into()), None, 0, 10),
+            event(Operation::Get, Some("a"), 11, 12),
+            event(Operation::Set("b".into()), Some("a"), 20, 30),
+            event(Operation::Get, Some("a"), 40, 50),
+        ],
+    };
+    let violations = history.check();
+    assert_eq!(violations.len(), 1);
+    let minimal: Vec<String> = violations[0].events.iter().map(Event::to_string).collect();
+    assert_eq!(minimal, vec!["SET k b => a", "GET k => a"]);
+}
+
+#[test]
+fn test_read_of_unwritten_value_is_reported() {
+    let history = History {
+        initial: HashMap::new(),
+        events: vec![
+            event(Operation::Get, Some("x"), 0, 10),
+            event(Operation::Set("a".into()), Some("x"), 20, 30),
+        ],
+    };
+    let violations = history.check();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].events.len(), 1);
+    assert_eq!(violations[0].events[0].to_string(), "GET k => x");
+}
+
+#[test]
+fn test_read_of_preloaded_value_is_linearizable() {
+    let history = History {
+        events: vec![event(Operation::Get, Some("x"), 0, 10)],
+        initial: HashMap::new(),
+    };
+    assert_eq!(history.check().len(), 1);
+
+    let history = history.with_initial(HashMap::from([("k".to_string(), "x".to_string())]));
+    assert!(history.check().is_empty());
+}
+
+#[test]
+fn test_write_without_response_may_have_taken_effect() {
+    use crate::pattern::{basic::BasicPattern, CommandSpan};
+    use crate::supplier::TimeResult;
+    use std::sync::Arc;
+
+    let pending = Event {
+        completed: PENDING,
+        ..event(Operation::Set("b".into()), None, 20, 0)
+    };
+    for (read, linearizable) in [("b", true), ("a", true), ("c", false)] {
+        let history = History {
+            initial: HashMap::new(),
+            events: vec![
+                event(Operation::Set("a".into()), None, 0, 10),
+                pending.clone(),
+                event(Operation::Get, Some(read), 30, 40),
+            ],
+        };
+        assert_eq!(
+            history.check().is_empty(),
+            linearizable,
+            "GET k => {}",
+            read
+        );
+    }
+
+    // the SET was sent before the connection failed, the GET never was
+    let origin = Instant::now();
+    let set = BasicCommand::Set {
+        key: "k".into(),
+        value: "b".into(),
+    };
+    let get = BasicCommand::Get { key: "k".into() };
+    let response = PatternResponse {
+        timing: TimeResult {
+            durations: vec![
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()),
+                Err(PatternExecError::not_sent()),
+            ],
+            first_byte_durations: vec![None, None],
+            spans: vec![CommandSpan {
+                invoked: origin,
+                completed: origin,
+            }],
+            total_duration: Default::default(),
+            start_time: origin,
+            handshake_duration: None,
+            connect_duration: None,
+            close_duration: None,
+        },
+        pattern: Arc::new(BasicPattern(vec![set, get], vec![], None)),
+        target: 0,
+    };
+    let history = History::from_responses([&response], origin);
+    assert_eq!(history.len(), 1);
+    assert!(history.events[0].is_pending());
+}
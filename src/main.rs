@@ -1,22 +1,12 @@
-#![allow(clippy::upper_case_acronyms)]
-
 use std::error::Error;
 
-use benchmark::perform_benchmark;
 use clap::Parser;
-use options::Commands;
-use test::perform_test;
-
-use crate::{generator::generate, options::Cli};
-
-pub(crate) mod benchmark;
-pub(crate) mod generator;
-pub(crate) mod options;
-pub(crate) mod pattern;
-pub(crate) mod results;
-pub(crate) mod supplier;
-pub(crate) mod test;
-pub(crate) mod worker;
+use server_language_client::{
+    benchmark_config, capture, export_text, fuzz, import_text,
+    options::{Cli, Commands},
+    replay_fuzz_input, run_conformance, run_proxy, Connector, FaultConfig, GenerateConfig,
+    TestConfig, ThinkTimes,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
@@ -29,17 +19,28 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             key_size,
             value_size,
             compression_level,
+            partitions,
+            think_time,
+            pattern_think_time,
+            preload,
+            routing,
         } => {
             println!("generating");
-            generate(
-                size,
-                data_out,
-                pattern,
-                key_size,
-                value_size,
-                compression_level,
-            )
-            .await?;
+            GenerateConfig::new(data_out)
+                .size(size)
+                .pattern(pattern)
+                .key_size(key_size)
+                .value_size(value_size)
+                .compression_level(compression_level)
+                .partitions(partitions)
+                .think_times(ThinkTimes {
+                    command: think_time,
+                    pattern: pattern_think_time,
+                })
+                .preload(preload)
+                .routing(routing)
+                .generate()
+                .await?;
         }
         Commands::Test {
             repetitions,
@@ -47,16 +48,98 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             pattern,
             key_size,
             value_size,
+            conformance,
+            max_key_size,
+            max_value_size,
+            tls,
+        } => {
+            if conformance {
+                let connector = Connector::new(vec![host], &tls)?;
+                run_conformance(&connector, max_key_size, max_value_size).await?;
+            } else {
+                TestConfig::new(host)
+                    .repetitions(repetitions)
+                    .pattern(pattern)
+                    .key_size(key_size)
+                    .value_size(value_size)
+                    .tls(tls)
+                    .run()
+                    .await?
+                    .print();
+            }
+        }
+        Commands::Benchmark(args) => {
+            let config = benchmark_config(args).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            });
+            let config = config.fd_limit(cli.fd_limit);
+            if config.is_auto_tune() {
+                config.tune().await?.print();
+            } else {
+                config.run().await?.print();
+            }
+        }
+        Commands::Export { data_in, text_out } => {
+            let patterns = export_text(data_in, &text_out).await?;
+            println!("exported {} patterns to {:?}", patterns, text_out);
+        }
+        Commands::Import {
+            text_in,
+            data_out,
+            compression_level,
+            predictions,
+        } => {
+            let patterns = import_text(text_in, &data_out, compression_level, predictions).await?;
+            println!("imported {} patterns into {:?}", patterns, data_out);
+        }
+        Commands::Capture {
+            listen,
+            upstream,
+            data_out,
+            compression_level,
+            limit,
+        } => {
+            capture(listen, upstream, data_out, compression_level, limit).await?;
+        }
+        Commands::Proxy {
+            listen,
+            upstream,
+            latency,
+            jitter,
+            bandwidth,
+            fragment,
+            drop_rate,
+            reset_rate,
+            seed,
         } => {
-            perform_test(repetitions, host, pattern, key_size, value_size).await?;
+            let faults = FaultConfig {
+                latency,
+                jitter,
+                bandwidth,
+                fragment,
+                drop_rate,
+                reset_rate,
+            };
+            run_proxy(listen, upstream, faults, seed).await?;
         }
-        Commands::Benchmark {
-            duration,
-            inp_file,
-            out_file,
+        Commands::Fuzz {
             host,
+            iterations,
+            seed,
+            restart_command,
+            out,
+            replay,
+            tls,
         } => {
-            perform_benchmark(duration, inp_file, out_file, host, cli.fd_limit).await?;
+            let connector = Connector::new(vec![host], &tls)?;
+            let survived = match replay {
+                Some(input) => replay_fuzz_input(connector, &input).await?,
+                None => fuzz(connector, iterations, seed, restart_command, &out).await?,
+            };
+            if !survived {
+                return Err("the server failed".into());
+            }
         }
     }
     Ok(())
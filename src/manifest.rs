@@ -0,0 +1,280 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::Serialize;
+
+use crate::benchmark::BenchmarkConfig;
+use crate::datafile::DataFileMetadata;
+use crate::generator::GenerateConfig;
+use crate::monitor::ClientReport;
+use crate::routing::Routing;
+use crate::think_time::ThinkTimes;
+use crate::worker::ConnectionMode;
+
+/// Everything needed to interpret the results of a benchmark run long after
+/// it happened, written next to the results.
+#[derive(Debug, Serialize)]
+pub(crate) struct Manifest {
+    client_version: &'static str,
+    /// wall-clock time the benchmark started, RFC 3339
+    start: String,
+    /// wall-clock time the results were recorded, RFC 3339
+    end: String,
+    workers: usize,
+    command_line: Vec<String>,
+    environment: Environment,
+    config: ConfigRecord,
+    data_file: DataFileRecord,
+    /// how busy the client itself was during the run
+    client: ClientReport,
+}
+
+impl Manifest {
+    pub(crate) fn new(
+        config: ConfigRecord,
+        data_file: DataFileRecord,
+        client: ClientReport,
+        workers: usize,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> Self {
+        Self {
+            client_version: env!("CARGO_PKG_VERSION"),
+            start: humantime::format_rfc3339_millis(start).to_string(),
+            end: humantime::format_rfc3339_millis(end).to_string(),
+            workers,
+            command_line: std::env::args().collect(),
+            environment: Environment::detect(),
+            config,
+            data_file,
+            client,
+        }
+    }
+
+    /// Path of the manifest belonging to a results file, `result.csv` gets
+    /// `result.manifest.toml`.
+    pub(crate) fn path_for(out_file: &Path) -> PathBuf {
+        out_file.with_extension("manifest.toml")
+    }
+
+    pub(crate) fn write(
+        &self,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// The machine the client ran on.
+#[derive(Debug, Serialize)]
+struct Environment {
+    hostname: Option<String>,
+    os: &'static str,
+    kernel: Option<String>,
+    cpu_model: Option<String>,
+    cpus: usize,
+}
+
+impl Environment {
+    fn detect() -> Self {
+        let cpu_model = std::fs::read_to_string("/proc/cpuinfo")
+            .ok()
+            .and_then(|info| {
+                info.lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(key, _)| key.trim() == "model name")
+                    .map(|(_, model)| model.trim().to_string())
+            });
+        Self {
+            hostname: hostname::get()
+                .ok()
+                .map(|name| name.to_string_lossy().into_owned()),
+            os: std::env::consts::OS,
+            kernel: std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                .ok()
+                .map(|release| release.trim().to_string()),
+            cpu_model,
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    humantime::format_duration(duration).to_string()
+}
+
+fn format_think_times(think_times: &ThinkTimes) -> (Option<String>, Option<String>) {
+    (
+        think_times.command.map(|t| t.to_string()),
+        think_times.pattern.map(|t| t.to_string()),
+    )
+}
+
+/// The resolved benchmark configuration, after merging the command line and
+/// the scenario file.
+#[derive(Debug, Serialize)]
+pub(crate) struct ConfigRecord {
+    duration: String,
+    warmup: String,
+    concurrency: Option<usize>,
+    connection: ConnectionMode,
+    inp_file: PathBuf,
+    in_memory: bool,
+    pattern_range: Option<String>,
+    out_file: Option<PathBuf>,
+    targets: Vec<String>,
+    routing: Routing,
+    check_linearizability: bool,
+    think_time: Option<String>,
+    pattern_think_time: Option<String>,
+    key_prefix: Option<String>,
+    salt: bool,
+    cleanup: bool,
+    server_pids: Vec<u32>,
+    auto_tune: Option<String>,
+    tls: bool,
+    tls_ca: Option<PathBuf>,
+    tls_cert: Option<PathBuf>,
+    tls_sni: Option<String>,
+    /// limit on open file descriptors the worker count is derived from
+    fd_limit: Option<u64>,
+    generate: Option<GenerateRecord>,
+}
+
+impl From<&BenchmarkConfig> for ConfigRecord {
+    fn from(config: &BenchmarkConfig) -> Self {
+        let (think_time, pattern_think_time) = format_think_times(&config.think_times);
+        Self {
+            duration: format_duration(config.duration),
+            warmup: format_duration(config.warmup),
+            concurrency: config.concurrency,
+            connection: config.connection,
+            inp_file: config.inp_file.clone(),
+            in_memory: config.in_memory,
+            pattern_range: config.pattern_range.map(|range| range.to_string()),
+            out_file: config.out_file.clone(),
+            targets: config.targets.iter().map(ToString::to_string).collect(),
+            routing: config.routing,
+            check_linearizability: config.check_linearizability,
+            think_time,
+            pattern_think_time,
+            key_prefix: config.key_prefix.clone(),
+            salt: config.salt,
+            cleanup: config.cleanup,
+            server_pids: config.server_pids.clone(),
+            auto_tune: config.auto_tune.map(|slo| slo.to_string()),
+            tls: config.tls.tls,
+            tls_ca: config.tls.tls_ca.clone(),
+            tls_cert: config.tls.tls_cert.clone(),
+            tls_sni: config.tls.tls_sni.clone(),
+            fd_limit: config.fd_limit,
+            generate: config.generate.as_ref().map(GenerateRecord::from),
+        }
+    }
+}
+
+/// Settings the data file was generated with before the run.
+#[derive(Debug, Serialize)]
+struct GenerateRecord {
+    size: usize,
+    pattern: String,
+    key_size: usize,
+    value_size: String,
+    compression_level: i32,
+    partitions: u32,
+    think_time: Option<String>,
+    pattern_think_time: Option<String>,
+    preload: usize,
+    routing: Routing,
+}
+
+impl From<&GenerateConfig> for GenerateRecord {
+    fn from(config: &GenerateConfig) -> Self {
+        let (think_time, pattern_think_time) = format_think_times(&config.think_times);
+        Self {
+            size: config.size,
+            pattern: config.pattern.to_string(),
+            key_size: config.key_size,
+            value_size: config.value_size.to_string(),
+            compression_level: config.compression_level,
+            partitions: config.partitions,
+            think_time,
+            pattern_think_time,
+            preload: config.preload,
+            routing: config.routing,
+        }
+    }
+}
+
+/// The metadata stored in the data file.
+#[derive(Debug, Serialize)]
+pub(crate) struct DataFileRecord {
+    version: u32,
+    partitions: u32,
+    think_time: Option<String>,
+    pattern_think_time: Option<String>,
+    value_size: Option<String>,
+    preload: u64,
+}
+
+impl From<&DataFileMetadata> for DataFileRecord {
+    fn from(metadata: &DataFileMetadata) -> Self {
+        let (think_time, pattern_think_time) = format_think_times(&metadata.think_times);
+        Self {
+            version: metadata.version,
+            partitions: metadata.partitions,
+            think_time,
+            pattern_think_time,
+            value_size: metadata.value_size.as_ref().map(ToString::to_string),
+            preload: metadata.preload,
+        }
+    }
+}
+
+#[test]
+fn test_manifest_records_config_and_environment() {
+    use crate::connection::Target;
+
+    let config = BenchmarkConfig::new(
+        vec!["127.0.0.1:8080".parse::<Target>().unwrap()],
+        "data.bin",
+        Duration::from_secs(10),
+    )
+    .generate(GenerateConfig::new("data.bin").preload(5))
+    .concurrency(4)
+    .fd_limit(1024);
+    let metadata = DataFileMetadata {
+        preload: 5,
+        ..DataFileMetadata::default()
+    };
+    let start = SystemTime::now();
+    let manifest = Manifest::new(
+        ConfigRecord::from(&config),
+        DataFileRecord::from(&metadata),
+        ClientReport::default(),
+        4,
+        start,
+        start + Duration::from_secs(10),
+    );
+
+    let manifest: toml::Table = toml::to_string(&manifest).unwrap().parse().unwrap();
+    assert_eq!(manifest["workers"].as_integer(), Some(4));
+    assert_eq!(manifest["config"]["fd_limit"].as_integer(), Some(1024));
+    assert_eq!(manifest["config"]["duration"].as_str(), Some("10s"));
+    assert_eq!(
+        manifest["config"]["targets"][0].as_str(),
+        Some("127.0.0.1:8080")
+    );
+    assert_eq!(
+        manifest["config"]["generate"]["pattern"].as_str(),
+        Some("SET-GET-GET-DEL:1")
+    );
+    assert_eq!(manifest["data_file"]["preload"].as_integer(), Some(5));
+    assert!(manifest["client"]["warnings"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+    assert!(manifest["environment"]["cpus"].as_integer().unwrap() >= 1);
+    assert!(manifest["start"].as_str().unwrap() < manifest["end"].as_str().unwrap());
+}
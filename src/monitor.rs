@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+
+use crate::pattern::ExecPattern;
+use crate::supplier::PatternBundle;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+/// Share of all cores, of the busiest core or of the core time of the busiest
+/// client thread above which the client counts as saturated.
+const CPU_SATURATION: f64 = 0.9;
+/// Share of samples with empty worker queues above which the workers count as
+/// starved.
+const STARVATION: f64 = 0.25;
+
+/// Counters the benchmark tasks update while the monitor samples them.
+#[derive(Debug, Default)]
+pub(crate) struct ClientCounters {
+    /// patterns decoded from the data file
+    decoded: AtomicU64,
+    /// workers currently executing a pattern, stands in for the alive and
+    /// queued task counts of the runtime, which tokio only exposes when built
+    /// with `--cfg tokio_unstable`
+    busy_workers: AtomicUsize,
+}
+
+impl ClientCounters {
+    pub(crate) fn pattern_decoded(&self) {
+        self.decoded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the worker as busy until the guard is dropped.
+    pub(crate) fn busy(&self) -> BusyGuard<'_> {
+        self.busy_workers.fetch_add(1, Ordering::Relaxed);
+        BusyGuard(&self.busy_workers)
+    }
+}
+
+pub(crate) struct BusyGuard<'a>(&'a AtomicUsize);
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Queues between the decoder, the feeder and the workers.
+pub(crate) struct Queues {
+    pub(crate) decoder: Sender<Arc<ExecPattern>>,
+    pub(crate) decoder_size: usize,
+    pub(crate) workers: Vec<flume::Sender<PatternBundle>>,
+}
+
+/// Share of the queue slots that are taken, given the queued patterns and the
+/// size of every queue.
+fn fill(queues: impl Iterator<Item = (usize, usize)>) -> f64 {
+    let (mut queued, mut slots) = (0, 0);
+    for (len, size) in queues {
+        queued += len;
+        slots += size;
+    }
+    if slots == 0 {
+        return 0.0;
+    }
+    queued as f64 / slots as f64
+}
+
+#[derive(Debug)]
+struct Sample {
+    /// CPU time used per wall-clock time, as a share of all cores
+    cpu: Option<f64>,
+    /// busy share of the busiest core, including other processes
+    busiest_core: Option<f64>,
+    /// CPU time of the busiest client thread per wall-clock time
+    busiest_thread: Option<f64>,
+    decoded_per_second: f64,
+    decoder_queue_fill: f64,
+    worker_queue_fill: f64,
+    busy_workers: usize,
+}
+
+/// How busy the client itself was during the benchmark, recorded in the run
+/// manifest.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct ClientReport {
+    samples: usize,
+    cores: usize,
+    /// mean CPU usage as a share of all cores
+    cpu_mean: Option<f64>,
+    cpu_max: Option<f64>,
+    /// mean busy share of the busiest core of each sample
+    busiest_core_mean: Option<f64>,
+    /// mean share of a core used by the busiest client thread of each sample
+    busiest_thread_mean: Option<f64>,
+    /// patterns decoded per second
+    decode_rate_mean: f64,
+    /// mean share of the decoder queue that was filled
+    decoder_queue_fill_mean: f64,
+    /// mean share of the worker queues that was filled
+    worker_queue_fill_mean: f64,
+    /// share of samples in which every worker queue was empty
+    worker_queues_empty: f64,
+    busy_workers_mean: f64,
+    warnings: Vec<String>,
+}
+
+impl ClientReport {
+    fn new(samples: &[Sample], cores: usize) -> Self {
+        if samples.is_empty() {
+            return Self {
+                cores,
+                ..Self::default()
+            };
+        }
+        let mean =
+            |f: &dyn Fn(&Sample) -> f64| samples.iter().map(f).sum::<f64>() / samples.len() as f64;
+        let mean_of = |f: &dyn Fn(&Sample) -> Option<f64>| {
+            let values: Vec<f64> = samples.iter().filter_map(f).collect();
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        };
+        let cpu_mean = mean_of(&|s| s.cpu);
+        let cpu_max = samples.iter().filter_map(|s| s.cpu).reduce(f64::max);
+        let busiest_core_mean = mean_of(&|s| s.busiest_core);
+        let busiest_thread_mean = mean_of(&|s| s.busiest_thread);
+        let worker_queues_empty = mean(&|s| (s.worker_queue_fill == 0.0) as u8 as f64);
+        let decoder_queue_fill_mean = mean(&|s| s.decoder_queue_fill);
+
+        let mut warnings = Vec::new();
+        if let Some(cpu) = cpu_mean.filter(|cpu| *cpu > CPU_SATURATION) {
+            warnings.push(format!(
+                "the client used {:.0}% of its {} cores, the results may be limited by the client rather than the server",
+                cpu * 100.0,
+                cores
+            ));
+        }
+        if let Some(thread) = busiest_thread_mean.filter(|cpu| *cpu > CPU_SATURATION) {
+            warnings.push(format!(
+                "a client thread used {:.0}% of a core, the results may be limited by the client rather than the server",
+                thread * 100.0
+            ));
+        }
+        if let Some(core) = busiest_core_mean.filter(|cpu| *cpu > CPU_SATURATION) {
+            warnings.push(format!(
+                "the busiest core was {:.0}% busy, the results may be limited by the machine rather than the server",
+                core * 100.0
+            ));
+        }
+        if worker_queues_empty > STARVATION {
+            let culprit = if decoder_queue_fill_mean < 0.5 {
+                "the decoder could not keep up with the workers"
+            } else {
+                "the feeder could not keep up with the workers"
+            };
+            warnings.push(format!(
+                "{}, their queues were empty in {:.0}% of the samples",
+                culprit,
+                worker_queues_empty * 100.0
+            ));
+        }
+
+        Self {
+            samples: samples.len(),
+            cores,
+            cpu_mean,
+            cpu_max,
+            busiest_core_mean,
+            busiest_thread_mean,
+            decode_rate_mean: mean(&|s| s.decoded_per_second),
+            decoder_queue_fill_mean,
+            worker_queue_fill_mean: mean(&|s| s.worker_queue_fill),
+            worker_queues_empty,
+            busy_workers_mean: mean(&|s| s.busy_workers as f64),
+            warnings,
+        }
+    }
+
+    pub(crate) fn print(&self) {
+        if let Some(cpu) = self.cpu_mean {
+            println!(
+                "the client used {:.0}% of its {} cores on average",
+                cpu * 100.0,
+                self.cores
+            );
+        }
+        if let Some(thread) = self.busiest_thread_mean {
+            println!(
+                "the busiest client thread used {:.0}% of a core on average",
+                thread * 100.0
+            );
+        }
+        println!(
+            "decoded {:.0} patterns per second, worker queues were {:.0}% full on average",
+            self.decode_rate_mean,
+            self.worker_queue_fill_mean * 100.0
+        );
+        for warning in self.warnings.iter() {
+            println!("warning: {}", warning);
+        }
+    }
+}
+
+/// CPU time the process used so far.
+#[cfg(unix)]
+fn process_cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn process_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Length of a clock tick in `/proc`.
+#[cfg(target_os = "linux")]
+fn clock_tick() -> Option<Duration> {
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    (ticks > 0).then(|| Duration::from_secs(1) / ticks as u32)
+}
+
+/// CPU time each thread of the process used so far, by thread id.
+#[cfg(target_os = "linux")]
+fn thread_cpu_times() -> Option<HashMap<String, Duration>> {
+    let tick = clock_tick()?;
+    let mut times = HashMap::new();
+    for task in std::fs::read_dir("/proc/self/task").ok()? {
+        let task = task.ok()?;
+        // the thread may exit before its stat is read
+        let Ok(stat) = std::fs::read_to_string(task.path().join("stat")) else {
+            continue;
+        };
+        // utime and stime are the 14th and 15th field, the 2nd field is the
+        // command name in parentheses, which may contain spaces
+        let mut fields = stat
+            .get(stat.rfind(')')? + 1..)?
+            .split_whitespace()
+            .skip(11);
+        let utime: u32 = fields.next()?.parse().ok()?;
+        let stime: u32 = fields.next()?.parse().ok()?;
+        times.insert(
+            task.file_name().to_string_lossy().into_owned(),
+            tick * (utime + stime),
+        );
+    }
+    Some(times)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_cpu_times() -> Option<HashMap<String, Duration>> {
+    None
+}
+
+/// Busy and total clock ticks of every core so far.
+#[cfg(target_os = "linux")]
+fn core_ticks() -> Option<Vec<(u64, u64)>> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let mut cores = Vec::new();
+    for line in stat
+        .lines()
+        .filter(|l| l.starts_with("cpu") && !l.starts_with("cpu "))
+    {
+        let ticks: Vec<u64> = line
+            .split_whitespace()
+            .skip(1)
+            .map(|t| t.parse().ok())
+            .collect::<Option<_>>()?;
+        // idle and iowait
+        let idle = ticks.get(3)? + ticks.get(4).unwrap_or(&0);
+        let total: u64 = ticks.iter().sum();
+        cores.push((total - idle, total));
+    }
+    Some(cores)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn core_ticks() -> Option<Vec<(u64, u64)>> {
+    None
+}
+
+/// Busy share of the busiest core between two readings of `core_ticks`.
+fn busiest_core(last: &[(u64, u64)], current: &[(u64, u64)]) -> Option<f64> {
+    last.iter()
+        .zip(current)
+        .filter(|((_, last_total), (_, total))| total > last_total)
+        .map(|((last_busy, last_total), (busy, total))| {
+            busy.saturating_sub(*last_busy) as f64 / (total - last_total) as f64
+        })
+        .reduce(f64::max)
+}
+
+/// Share of a core used by the busiest thread between two readings of
+/// `thread_cpu_times`, threads started in between are skipped.
+fn busiest_thread(
+    last: &HashMap<String, Duration>,
+    current: &HashMap<String, Duration>,
+    elapsed: f64,
+) -> Option<f64> {
+    current
+        .iter()
+        .filter_map(|(tid, time)| Some(time.saturating_sub(*last.get(tid)?)))
+        .map(|time| time.as_secs_f64() / elapsed)
+        .reduce(f64::max)
+}
+
+/// Samples the client until the kill switch fires.
+pub(crate) async fn monitor_client(
+    counters: Arc<ClientCounters>,
+    queues: Queues,
+    mut kill_switch: tokio::sync::watch::Receiver<()>,
+) -> ClientReport {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut samples = Vec::new();
+    let mut last_time = Instant::now();
+    let mut last_cpu = process_cpu_time();
+    let mut last_threads = thread_cpu_times();
+    let mut last_cores = core_ticks();
+    let mut last_decoded = counters.decoded.load(Ordering::Relaxed);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(SAMPLE_INTERVAL) => {}
+            _ = kill_switch.changed() => break,
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(last_time).as_secs_f64();
+        let cpu_time = process_cpu_time();
+        let threads = thread_cpu_times();
+        let cores_ticks = core_ticks();
+        let decoded = counters.decoded.load(Ordering::Relaxed);
+        let cpu = match (last_cpu, cpu_time) {
+            (Some(last), Some(current)) => {
+                Some((current - last).as_secs_f64() / elapsed / cores as f64)
+            }
+            _ => None,
+        };
+
+        samples.push(Sample {
+            cpu,
+            busiest_core: last_cores
+                .as_deref()
+                .zip(cores_ticks.as_deref())
+                .and_then(|(last, current)| busiest_core(last, current)),
+            busiest_thread: last_threads
+                .as_ref()
+                .zip(threads.as_ref())
+                .and_then(|(last, current)| busiest_thread(last, current, elapsed)),
+            decoded_per_second: (decoded - last_decoded) as f64 / elapsed,
+            decoder_queue_fill: fill(std::iter::once((
+                queues.decoder_size - queues.decoder.capacity(),
+                queues.decoder_size,
+            ))),
+            worker_queue_fill: fill(
+                queues
+                    .workers
+                    .iter()
+                    .map(|s| (s.len(), s.capacity().unwrap_or_default())),
+            ),
+            busy_workers: counters.busy_workers.load(Ordering::Relaxed),
+        });
+
+        last_time = now;
+        last_cpu = cpu_time;
+        last_threads = threads;
+        last_cores = cores_ticks;
+        last_decoded = decoded;
+    }
+
+    ClientReport::new(&samples, cores)
+}
+
+#[test]
+fn test_client_report_warnings() {
+    let sample = |cpu, decoder_queue_fill, worker_queue_fill| Sample {
+        cpu: Some(cpu),
+        busiest_core: Some(cpu),
+        busiest_thread: Some(cpu),
+        decoded_per_second: 1000.0,
+        decoder_queue_fill,
+        worker_queue_fill,
+        busy_workers: 2,
+    };
+
+    let healthy = ClientReport::new(&[sample(0.2, 1.0, 0.8), sample(0.4, 1.0, 0.6)], 4);
+    assert!(healthy.warnings.is_empty());
+    assert!((healthy.cpu_mean.unwrap() - 0.3).abs() < 1e-9);
+    assert_eq!(healthy.cpu_max, Some(0.4));
+
+    let saturated = ClientReport::new(&[sample(0.95, 0.0, 0.0), sample(1.0, 0.0, 0.0)], 4);
+    assert_eq!(saturated.warnings.len(), 4);
+    assert!(saturated.warnings[0].contains("client"));
+    assert!(saturated.warnings[3].contains("decoder"));
+
+    // one saturated thread hides in the mean over all cores
+    let one_thread = Sample {
+        busiest_thread: Some(1.0),
+        ..sample(0.25, 1.0, 0.8)
+    };
+    let report = ClientReport::new(&[one_thread], 4);
+    assert_eq!(report.warnings.len(), 1);
+    assert!(report.warnings[0].contains("thread"));
+}
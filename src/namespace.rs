@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::connection::Connector;
+use crate::datafile::DataFile;
+use crate::pattern::basic::BasicCommand;
+use crate::pattern::ExecPattern;
+use crate::routing::Routing;
+use crate::worker::execute_bulk;
+
+const SALT_LENGTH: usize = 8;
+
+/// Prefix put in front of every key of a run, so runs against a long-lived
+/// server neither see each other's keys nor leave keys behind that can't be
+/// told apart.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct KeyNamespace {
+    prefix: String,
+}
+
+impl KeyNamespace {
+    /// Namespace made of the given prefix followed by a random salt, if
+    /// `salt` is set, which makes the keys unique to this run.
+    pub(crate) fn new(prefix: Option<String>, salt: bool) -> Self {
+        let mut prefix = prefix.unwrap_or_default();
+        if salt {
+            let salt: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(SALT_LENGTH)
+                .map(char::from)
+                .collect();
+            prefix.push_str(&salt);
+            prefix.push('-');
+        }
+        Self { prefix }
+    }
+
+    pub(crate) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.prefix.is_empty()
+    }
+
+    /// The key moved into the namespace.
+    pub(crate) fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Moves all keys of the pattern into the namespace.
+    pub(crate) fn apply(&self, pattern: &mut ExecPattern) {
+        if self.is_empty() {
+            return;
+        }
+        for command in pattern.0.iter_mut() {
+            let key = match command {
+                BasicCommand::Get { key }
+                | BasicCommand::Set { key, .. }
+                | BasicCommand::Del { key } => key,
+            };
+            key.insert_str(0, &self.prefix);
+        }
+    }
+}
+
+/// Deletes every key the data file preloads or the patterns in `range` set,
+/// in the namespace of the run. Returns the number of keys that still existed.
+pub(crate) async fn cleanup(
+    data_file: &DataFile,
+    range: Range<u64>,
+    namespace: &KeyNamespace,
+    connector: Arc<Connector>,
+    routing: Routing,
+    connections: usize,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut keys: HashSet<String> = data_file
+        .read_preload()
+        .await?
+        .into_iter()
+        .map(|(key, _)| namespace.key(&key))
+        .collect();
+    for chunk in data_file.chunk_of(range.start)..=data_file.chunk_of(range.end - 1) {
+        let first = data_file.chunks()[chunk].first_pattern;
+        for (position, mut pattern) in (first..).zip(data_file.read_chunk(chunk).await?) {
+            if !range.contains(&position) {
+                continue;
+            }
+            namespace.apply(&mut pattern);
+            for command in pattern.0 {
+                if let BasicCommand::Set { key, .. } = command {
+                    keys.insert(key);
+                }
+            }
+        }
+    }
+
+    println!("cleaning up {} keys", keys.len());
+
+    let commands = keys
+        .into_iter()
+        .map(|key| BasicCommand::Del { key })
+        .collect();
+    execute_bulk(commands, connector, routing, connections).await
+}
+
+#[test]
+fn test_namespace_prefixes_all_keys() {
+    use crate::pattern::basic::BasicPattern;
+
+    let mut pattern = BasicPattern(
+        vec![
+            BasicCommand::Set {
+                key: "a".into(),
+                value: "v".into(),
+            },
+            BasicCommand::Get { key: "a".into() },
+            BasicCommand::Del { key: "b".into() },
+        ],
+        vec!["not found\n".into(), "v\n".into(), "not found\n".into()],
+        None,
+    );
+
+    let namespace = KeyNamespace::new(Some("run:".into()), true);
+    assert_eq!(namespace.prefix().len(), "run:".len() + SALT_LENGTH + 1);
+    assert_ne!(
+        namespace.prefix(),
+        KeyNamespace::new(Some("run:".into()), true).prefix()
+    );
+
+    namespace.apply(&mut pattern);
+    let keys: Vec<&str> = pattern.0.iter().map(BasicCommand::key).collect();
+    assert_eq!(
+        keys,
+        vec![
+            format!("{}a", namespace.prefix()),
+            format!("{}a", namespace.prefix()),
+            format!("{}b", namespace.prefix())
+        ]
+    );
+    assert_eq!(pattern.1[1], "v\n");
+}
@@ -1,11 +1,21 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 #[cfg(unix)]
 use libc::{getrlimit, rlimit, setrlimit, RLIMIT_NOFILE};
 
-use crate::pattern::ParsePattern as Pattern;
+use crate::autotune::Slo;
+use crate::connection::Target;
+use crate::datafile::PatternRange;
+use crate::generator::parse_key_size;
+use crate::pattern::PatternMix;
+use crate::proxy::parse_probability;
+use crate::routing::Routing;
+use crate::text::Predictions;
+use crate::think_time::ThinkTime;
+use crate::value_size::ValueSize;
+use crate::worker::ConnectionMode;
 
 #[cfg(unix)]
 #[allow(clippy::useless_conversion)]
@@ -47,18 +57,135 @@ fn get_file_descriptor_limit() -> u64 {
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-pub(crate) struct Cli {
+pub struct Cli {
     /// subcommand
     #[clap(subcommand)]
-    pub(crate) command: Commands,
+    pub command: Commands,
     /// limit on the number of concurrent file descriptors
     /// that can be open concurrently
     #[clap(short = 'l', long, default_value_t = get_file_descriptor_limit())]
-    pub(crate) fd_limit: u64,
+    pub fd_limit: u64,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct TlsArgs {
+    /// connect to the server using TLS
+    #[clap(long)]
+    pub tls: bool,
+    /// PEM file with the certificates used to verify the server, the webpki
+    /// roots are used if omitted
+    #[clap(long, requires = "tls")]
+    pub tls_ca: Option<PathBuf>,
+    /// PEM file with the client certificate chain
+    #[clap(long, requires_all = &["tls", "tls-key"])]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the client certificate
+    #[clap(long, requires_all = &["tls", "tls-cert"])]
+    pub tls_key: Option<PathBuf>,
+    /// server name sent via SNI and verified against the server certificate
+    #[clap(long, requires = "tls")]
+    pub tls_sni: Option<String>,
+}
+
+/// Settings of a benchmark run. Everything left out is taken from the
+/// scenario file, if one is given, and falls back to the defaults otherwise.
+#[derive(Args, Debug, Clone)]
+pub struct BenchmarkArgs {
+    /// how long the benchmark runs, excluding the warm-up
+    #[clap(parse(try_from_str=parse_duration::parse))]
+    pub(crate) duration: Option<std::time::Duration>,
+    /// data file the patterns are read from [default: data.bin]
+    pub(crate) inp_file: Option<PathBuf>,
+    /// file the results are written to [default: result.csv]
+    pub(crate) out_file: Option<PathBuf>,
+    /// hosts on which the servers are listening, either socket addresses
+    /// or unix domain socket paths prefixed with `unix:`
+    /// [default: 127.0.0.1:8080]
+    #[clap(parse(try_from_str), multiple_values = true)]
+    pub(crate) hosts: Vec<Target>,
+    /// TOML file describing the benchmark, options given on the command
+    /// line take precedence over it
+    #[clap(long)]
+    pub(crate) scenario: Option<PathBuf>,
+    /// number of workers executing patterns concurrently, derived from the
+    /// file descriptor limit and the number of cores if omitted
+    #[clap(long)]
+    pub(crate) concurrency: Option<usize>,
+    /// whether every pattern opens its own connection or workers keep their
+    /// connections open [default: per-pattern]
+    #[clap(long, arg_enum)]
+    pub(crate) connection: Option<ConnectionMode>,
+    /// load and decode the whole data file before the run and replay it from
+    /// memory, so decoding doesn't compete with the workers
+    #[clap(long, overrides_with = "no-in-memory")]
+    pub(crate) in_memory: bool,
+    /// stream the data file even if the scenario loads it into memory
+    #[clap(long, overrides_with = "in-memory")]
+    pub(crate) no_in_memory: bool,
+    /// replay only the patterns at these positions of the data file, e.g.
+    /// `0..50000` or `50000..`, so several clients can replay disjoint parts
+    /// of it, predictions only hold for ranges starting at 0
+    #[clap(long)]
+    pub(crate) pattern_range: Option<PatternRange>,
+    /// time the benchmark runs before results are recorded [default: 0s]
+    #[clap(long, parse(try_from_str=parse_duration::parse))]
+    pub(crate) warmup: Option<std::time::Duration>,
+    /// how patterns are distributed over multiple hosts
+    /// [default: round-robin]
+    #[clap(long, arg_enum)]
+    pub(crate) routing: Option<Routing>,
+    /// pause between the commands of a pattern, overrides the one stored in
+    /// the data file, e.g. `10ms`, `uniform:1ms..5ms` or `exp:2ms`
+    #[clap(long)]
+    pub(crate) think_time: Option<ThinkTime>,
+    /// pause between the patterns a worker executes, overrides the one
+    /// stored in the data file
+    #[clap(long)]
+    pub(crate) pattern_think_time: Option<ThinkTime>,
+    /// prefix put in front of every key of the run
+    #[clap(long)]
+    pub(crate) key_prefix: Option<String>,
+    /// add a random salt unique to the run to the key prefix, so keys left
+    /// over by earlier runs can't affect the predictions
+    #[clap(long, overrides_with = "no-salt")]
+    pub(crate) salt: bool,
+    /// don't salt the key prefix even if the scenario does
+    #[clap(long, overrides_with = "salt")]
+    pub(crate) no_salt: bool,
+    /// delete every key the run may have created after the benchmark
+    #[clap(long, overrides_with = "no-cleanup")]
+    pub(crate) cleanup: bool,
+    /// keep the keys of the run even if the scenario cleans them up
+    #[clap(long, overrides_with = "cleanup")]
+    pub(crate) no_cleanup: bool,
+    /// record the history of all operations and check it for
+    /// linearizability against a key-value register model
+    #[clap(long, overrides_with = "no-check-linearizability")]
+    pub(crate) check_linearizability: bool,
+    /// skip the linearizability check even if the scenario enables it
+    #[clap(long, overrides_with = "check-linearizability")]
+    pub(crate) no_check_linearizability: bool,
+    /// process id of a server whose CPU time, memory, threads and open file
+    /// descriptors are sampled during the run and written next to the
+    /// results, can be given once per server
+    #[clap(long = "server-pid")]
+    pub(crate) server_pids: Vec<u32>,
+    /// search for the highest concurrency that still meets a latency and
+    /// error objective, e.g. `p99<5ms` or `p99.9<10ms,errors<0.1%`, running
+    /// the benchmark once per step, `--concurrency` bounds the search, the
+    /// results of the best step are written to the output file and a summary
+    /// of every step next to it
+    #[clap(long)]
+    pub(crate) auto_tune: Option<Slo>,
+    /// connect without TLS even if the scenario enables it
+    #[clap(long, conflicts_with = "tls")]
+    pub(crate) no_tls: bool,
+    #[clap(flatten)]
+    pub(crate) tls: TlsArgs,
 }
 
 #[derive(Subcommand, Debug)]
-pub(crate) enum Commands {
+pub enum Commands {
     /// generate benchmark data
     Generate {
         /// amount of patterns to be generated
@@ -66,25 +193,52 @@ pub(crate) enum Commands {
         /// file for where to put the generated data
         #[clap(default_value = "data.bin")]
         data_out: PathBuf,
-        /// pattern to pass
+        /// pattern to pass, or several comma separated patterns with
+        /// weights, e.g. `GET:80,SET-GET:15,SET-GET-GET-DEL:5`
         #[clap(parse(try_from_str), default_value = "SET-GET-GET-DEL")]
-        pattern: Pattern,
+        pattern: PatternMix,
         /// key size in number of characters
-        #[clap(default_value_t = 10)]
+        #[clap(parse(try_from_str=parse_key_size), default_value_t = 10)]
         key_size: usize,
-        /// value size in number of characters
-        #[clap(default_value_t = 10)]
-        value_size: usize,
+        /// value size in number of characters, or a distribution the sizes
+        /// are drawn from: `uniform:10..100`, `normal:100,20`,
+        /// `lognormal:4.6,0.5`, `empirical:16=50,128=30,4096=20` or
+        /// `empirical:@file` with one `size weight` pair per line
+        #[clap(default_value = "10")]
+        value_size: ValueSize,
         #[clap(min_values(0), max_values(21), default_value_t = 0)]
         compression_level: i32,
+        /// split the key space into this many disjoint partitions, every
+        /// benchmark worker then owns the keys of its partitions, which keeps
+        /// predictions valid under concurrency
+        #[clap(long, default_value_t = 0)]
+        partitions: u32,
+        /// pause between the commands of a pattern, stored in the data file,
+        /// e.g. `10ms`, `uniform:1ms..5ms` or `exp:2ms`
+        #[clap(long)]
+        think_time: Option<ThinkTime>,
+        /// pause between the patterns a worker executes, stored in the data
+        /// file
+        #[clap(long)]
+        pattern_think_time: Option<ThinkTime>,
+        /// number of keys the benchmark sets before it starts, patterns that
+        /// only read use these keys, requires `--partitions`
+        #[clap(long, default_value_t = 0)]
+        preload: usize,
+        /// routing the file is generated for, with hash routing all commands
+        /// of a pattern act on the same key, so the pattern stays on the
+        /// host owning it
+        #[clap(long, arg_enum, default_value = "round-robin")]
+        routing: Routing,
     },
     Test {
         /// specify how often the given pattern should be repeated
         #[clap(default_value_t = 1)]
         repetitions: usize,
-        /// host on which the server is listening
-        #[clap(default_value = "0.0.0.0:8080")]
-        host: SocketAddr,
+        /// host on which the server is listening, either a socket address
+        /// or a unix domain socket path prefixed with `unix:`
+        #[clap(parse(try_from_str), default_value = "0.0.0.0:8080")]
+        host: Target,
         /// pattern that will be executed
         ///
         /// A pattern is defined as pattern key words, seperated by a `-`.
@@ -95,23 +249,134 @@ pub(crate) enum Commands {
         /// * GET
         ///
         /// * DEL
+        ///
+        /// Several patterns can be mixed by separating them with `,` and
+        /// giving each a weight, e.g. `GET:80,SET-GET:20`.
         #[clap(parse(try_from_str), default_value = "SET-GET-GET-DEL")]
-        pattern: Pattern,
+        pattern: PatternMix,
         /// the size of the generated keys
-        #[clap(default_value_t = 10)]
+        #[clap(parse(try_from_str=parse_key_size), default_value_t = 10)]
         key_size: usize,
-        /// the size of the generated values
-        #[clap(default_value_t = 10)]
-        value_size: usize,
+        /// the size of the generated values, or a distribution the sizes are
+        /// drawn from, see `generate`
+        #[clap(default_value = "10")]
+        value_size: ValueSize,
+        /// run the catalogue of protocol conformance cases instead of the
+        /// pattern and report which of them pass
+        #[clap(long)]
+        conformance: bool,
+        /// longest key the conformance cases expect the server to accept
+        #[clap(long, default_value_t = 256)]
+        max_key_size: usize,
+        /// longest value the conformance cases expect the server to accept
+        #[clap(long, default_value_t = 4096)]
+        max_value_size: usize,
+        #[clap(flatten)]
+        tls: TlsArgs,
+    },
+    Benchmark(BenchmarkArgs),
+    /// write the patterns of a data file as text, one pattern per line
+    Export {
+        /// data file to convert
+        #[clap(default_value = "data.bin")]
+        data_in: PathBuf,
+        /// file for where to put the text
+        #[clap(default_value = "data.txt")]
+        text_out: PathBuf,
+    },
+    /// convert a text file of patterns, as written by export, into a data
+    /// file
+    Import {
+        /// text file to convert
+        #[clap(default_value = "data.txt")]
+        text_in: PathBuf,
+        /// file for where to put the converted data
+        #[clap(default_value = "data.bin")]
+        data_out: PathBuf,
+        #[clap(min_values(0), max_values(21), default_value_t = 0)]
+        compression_level: i32,
+        /// whether the predicted responses are taken from the text file or
+        /// recomputed from the commands
+        #[clap(long, arg_enum, default_value = "verbatim")]
+        predictions: Predictions,
     },
-    Benchmark {
-        #[clap(parse(try_from_str=parse_duration::parse))]
-        duration: std::time::Duration,
+    /// record client sessions against a server into a data file
+    Capture {
+        /// address the recording proxy listens on
+        #[clap(default_value = "127.0.0.1:8081")]
+        listen: SocketAddr,
+        /// address of the server the traffic is forwarded to
+        #[clap(default_value = "127.0.0.1:8080")]
+        upstream: SocketAddr,
+        /// file for where to put the captured patterns
         #[clap(default_value = "data.bin")]
-        inp_file: PathBuf,
-        #[clap(default_value = "result.csv")]
-        out_file: PathBuf,
+        data_out: PathBuf,
+        #[clap(min_values(0), max_values(21), default_value_t = 0)]
+        compression_level: i32,
+        /// stop after this many patterns have been captured
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// forward traffic to a server while injecting network faults
+    Proxy {
+        /// address the proxy listens on
+        #[clap(default_value = "127.0.0.1:8081")]
+        listen: SocketAddr,
+        /// address of the server the traffic is forwarded to
         #[clap(default_value = "127.0.0.1:8080")]
-        host: SocketAddr,
+        upstream: SocketAddr,
+        /// fixed delay added to every forwarded chunk
+        #[clap(long, parse(try_from_str=parse_duration::parse), default_value = "0s")]
+        latency: std::time::Duration,
+        /// maximum random delay added on top of the latency
+        #[clap(long, parse(try_from_str=parse_duration::parse), default_value = "0s")]
+        jitter: std::time::Duration,
+        /// maximum throughput per direction in bytes per second
+        #[clap(long)]
+        bandwidth: Option<u64>,
+        /// forward data one byte at a time
+        #[clap(long)]
+        fragment: bool,
+        /// probability per forwarded chunk of closing the connection
+        #[clap(long, parse(try_from_str=parse_probability), default_value_t = 0.0)]
+        drop_rate: f64,
+        /// probability per forwarded chunk of resetting the connection
+        #[clap(long, parse(try_from_str=parse_probability), default_value_t = 0.0)]
+        reset_rate: f64,
+        /// seed for the fault decisions, makes runs reproducible
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+    /// send malformed input to a server until it crashes or hangs
+    Fuzz {
+        /// host on which the server is listening, either a socket address
+        /// or a unix domain socket path prefixed with `unix:`
+        #[clap(parse(try_from_str), default_value = "127.0.0.1:8080")]
+        host: Target,
+        /// number of inputs to send
+        #[clap(long, default_value_t = 10_000)]
+        iterations: usize,
+        /// seed for the generated inputs, makes runs reproducible
+        #[clap(long)]
+        seed: Option<u64>,
+        /// shell command restarting the server, needed to minimize the
+        /// input that made it fail
+        #[clap(long)]
+        restart_command: Option<String>,
+        /// file the input that made the server fail is written to
+        #[clap(long, default_value = "fuzz-crash.bin")]
+        out: PathBuf,
+        /// send an input saved by an earlier run instead of fuzzing and
+        /// check that the server survives it
+        #[clap(long)]
+        replay: Option<PathBuf>,
+        #[clap(flatten)]
+        tls: TlsArgs,
     },
 }
+
+#[test]
+fn test_cli_definition() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert();
+}
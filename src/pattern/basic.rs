@@ -1,83 +1,164 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::time::Duration;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
-    net::TcpStream,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream},
     time::Instant,
 };
 
-use crate::generator::generate_valid_string;
+use crate::generator::{generate_key, generate_valid_string, Partition, PreloadedKeys};
+use crate::supplier::TimeResult;
+use crate::think_time::{think, ThinkTime};
+use crate::value_size::ValueSizeSampler;
+use rand::thread_rng;
 
-use super::{ParsePattern, ParsePatternCommand, PatternExecError};
+use super::{CommandSpan, ParsePattern, ParsePatternCommand, PatternExecError};
 
+/// Commands, the responses predicted for them and the key partition all keys
+/// of the pattern belong to, if the key space was partitioned.
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct BasicPattern(pub(crate) Vec<BasicCommand>, pub(crate) Vec<String>);
+pub struct BasicPattern(
+    pub(crate) Vec<BasicCommand>,
+    pub(crate) Vec<String>,
+    pub(crate) Option<u32>,
+);
 
 impl BasicPattern {
     pub(crate) fn new(
         p: &ParsePattern,
         key_len: usize,
-        value_len: usize,
+        value_size: &ValueSizeSampler,
+        partition: Option<Partition>,
+        preloaded: Option<&PreloadedKeys>,
+        single_key: bool,
         state: &mut BasicState,
-    ) -> Self {
-        let mut current_set: Option<BasicCommand> = None;
+    ) -> Result<Self, String> {
+        let mut rng = thread_rng();
+        // preloaded keys are only read, so replaying the file again finds
+        // them unchanged
+        let read_only = p.0.iter().all(|c| matches!(c, ParsePatternCommand::GET));
+        let preloaded = preloaded.filter(|_| read_only);
+        // every SET writes a new key that the following commands act on,
+        // unless all commands have to act on the key of the first one, so
+        // routing by key sends the whole pattern to the host owning it
+        let mut current_key: Option<String> = None;
         let content: Vec<BasicCommand> =
             p.0.iter()
-                .map(|e| match e {
-                    ParsePatternCommand::SET => {
-                        let ret = generate_set(key_len, value_len);
-                        current_set = Some(ret.clone());
-                        ret
-                    }
-                    ParsePatternCommand::GET => match &current_set {
-                        Some(s) => derive_get(s),
-                        None => generate_get(key_len),
-                    },
-                    ParsePatternCommand::DEL => match &current_set {
-                        Some(s) => derive_del(s),
-                        None => generate_del(key_len),
-                    },
+                .map(|e| {
+                    let key = match (e, &current_key) {
+                        (ParsePatternCommand::SET, Some(key)) if single_key => key.clone(),
+                        (ParsePatternCommand::SET, _) => current_key
+                            .insert(generate_key(key_len, partition)?)
+                            .clone(),
+                        (_, Some(key)) => key.clone(),
+                        (_, None) => {
+                            let key = match preloaded.and_then(|p| p.choose(partition, &mut rng)) {
+                                Some(key) => key.to_string(),
+                                None => generate_key(key_len, partition)?,
+                            };
+                            if single_key {
+                                current_key = Some(key.clone());
+                            }
+                            key
+                        }
+                    };
+                    Ok(match e {
+                        ParsePatternCommand::SET => {
+                            let value_len = value_size.sample(&mut rng);
+                            BasicCommand::Set {
+                                key,
+                                value: generate_valid_string(value_len),
+                            }
+                        }
+                        ParsePatternCommand::GET => BasicCommand::Get { key },
+                        ParsePatternCommand::DEL => BasicCommand::Del { key },
+                    })
                 })
-                .collect();
+                .collect::<Result<_, String>>()?;
 
         let predictions = content.iter().map(|e| e.predict(state)).collect();
 
-        Self(content, predictions)
+        Ok(Self(content, predictions, partition.map(|p| p.index)))
     }
 
-    pub(crate) async fn execute(
+    /// Executes the commands one after another, pausing for the think time
+    /// between them. The pauses are excluded from the total duration.
+    pub(crate) async fn execute<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
-        conn: &mut BufStream<TcpStream>,
-    ) -> std::io::Result<(Vec<Result<Duration, PatternExecError>>, Duration)> {
-        let mut ret = Vec::with_capacity(self.0.len());
+        conn: &mut BufStream<S>,
+        think_time: Option<&ThinkTime>,
+    ) -> TimeResult {
+        let mut durations = Vec::with_capacity(self.0.len());
+        let mut first_byte_durations = Vec::with_capacity(self.0.len());
+        let mut spans = Vec::with_capacity(self.0.len());
+        let mut thought = Duration::ZERO;
         let start = tokio::time::Instant::now();
         for (idx, b) in self.0.iter().enumerate() {
+            if idx > 0 {
+                thought += think(think_time).await;
+            }
+            let invoked = Instant::now();
             let res = b.execute(conn, self.1.get(idx).unwrap().to_string()).await;
-            if let Err(PatternExecError::IoError(io)) = res {
-                return Err(io);
+            let completed = Instant::now();
+            let io_failed = matches!(res, Err(PatternExecError::IoError(_)));
+            first_byte_durations.push(res.as_ref().ok().map(|(first_byte, _)| *first_byte));
+            durations.push(res.map(|(_, duration)| duration));
+            spans.push(CommandSpan { invoked, completed });
+            if io_failed {
+                // the rest of the pattern can't be sent over the failed
+                // connection
+                for _ in idx + 1..self.0.len() {
+                    first_byte_durations.push(None);
+                    durations.push(Err(PatternExecError::not_sent()));
+                }
+                break;
             }
-            ret.push(res);
         }
-        let duration = start.elapsed();
-        Ok((ret, duration))
+        TimeResult {
+            durations,
+            first_byte_durations,
+            spans,
+            total_duration: start.elapsed().saturating_sub(thought),
+            start_time: start,
+            handshake_duration: None,
+            connect_duration: None,
+            close_duration: None,
+        }
+    }
+
+    /// Commands of the pattern, in the order they are executed.
+    pub fn commands(&self) -> &[BasicCommand] {
+        &self.0
+    }
+
+    /// Name of the pattern class this pattern belongs to, its commands
+    /// joined by `-`, e.g. `SET-GET-DEL`.
+    pub fn class(&self) -> String {
+        self.0
+            .iter()
+            .map(BasicCommand::name)
+            .collect::<Vec<_>>()
+            .join("-")
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) enum BasicCommand {
+pub enum BasicCommand {
     Get { key: String },
     Set { key: String, value: String },
     Del { key: String },
 }
 
 impl BasicCommand {
+    /// Returns the time until the first byte of the response arrived and
+    /// until the response was read completely.
     #[inline(always)]
-    async fn execute(
+    async fn execute<S: AsyncRead + AsyncWrite + Unpin>(
         &self,
-        conn: &mut BufStream<TcpStream>,
+        conn: &mut BufStream<S>,
         expected_response: String,
-    ) -> Result<Duration, PatternExecError> {
+    ) -> Result<(Duration, Duration), PatternExecError> {
         match self {
             BasicCommand::Get { ref key } => execute_get(conn, key, expected_response).await,
             BasicCommand::Set { ref key, ref value } => {
@@ -87,7 +168,25 @@ impl BasicCommand {
         }
     }
 
-    fn predict(&self, state: &mut BasicState) -> String {
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        match self {
+            BasicCommand::Get { .. } => "GET",
+            BasicCommand::Set { .. } => "SET",
+            BasicCommand::Del { .. } => "DEL",
+        }
+    }
+
+    #[inline]
+    pub fn key(&self) -> &str {
+        match self {
+            BasicCommand::Get { key }
+            | BasicCommand::Set { key, .. }
+            | BasicCommand::Del { key } => key,
+        }
+    }
+
+    pub(crate) fn predict(&self, state: &mut BasicState) -> String {
         match self {
             BasicCommand::Get { ref key } => predict_get(state, key),
             BasicCommand::Set { ref key, ref value } => predict_set(state, key, value),
@@ -96,119 +195,183 @@ impl BasicCommand {
     }
 }
 
-impl ToString for BasicCommand {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for BasicCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            BasicCommand::Get { ref key } => format!("GET {}", key),
-            BasicCommand::Set { ref key, ref value } => format!("SET {} {}", key, value),
-            BasicCommand::Del { ref key } => format!("DEL {}", key),
+            BasicCommand::Get { ref key } => write!(f, "GET {}", key),
+            BasicCommand::Set { ref key, ref value } => write!(f, "SET {} {}", key, value),
+            BasicCommand::Del { ref key } => write!(f, "DEL {}", key),
         }
     }
 }
 
-#[inline(always)]
-async fn execute_get(
-    conn: &mut BufStream<TcpStream>,
-    key: &str,
-    expected_response: String,
-) -> Result<Duration, PatternExecError> {
-    let command_string: String = format!("GET {}\n", key);
-
-    let start = Instant::now();
+impl FromStr for BasicCommand {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim_end_matches('\n').splitn(3, ' ');
+        let command = parts.next().ok_or("empty command")?;
+        let key = parts.next().ok_or("missing key")?.to_string();
+        match (command, parts.next()) {
+            ("GET", None) => Ok(Self::Get { key }),
+            ("DEL", None) => Ok(Self::Del { key }),
+            ("SET", Some(value)) => Ok(Self::Set {
+                key,
+                value: value.to_string(),
+            }),
+            ("SET", None) => Err("missing value"),
+            ("GET" | "DEL", Some(_)) => Err("unexpected argument"),
+            _ => Err("invalid command"),
+        }
+    }
+}
 
-    conn.write_all(command_string.as_bytes()).await?;
-    conn.flush().await?;
+#[test]
+fn test_basic_command_round_trip() {
+    let commands = [
+        BasicCommand::Get { key: "a".into() },
+        BasicCommand::Set {
+            key: "b".into(),
+            value: "c".into(),
+        },
+        BasicCommand::Del { key: "d".into() },
+    ];
+    for command in commands {
+        let parsed: BasicCommand = command.to_string().parse().unwrap();
+        assert_eq!(parsed.to_string(), command.to_string());
+    }
+    assert!("GET".parse::<BasicCommand>().is_err());
+    assert!("SET a".parse::<BasicCommand>().is_err());
+    assert!("PUT a b".parse::<BasicCommand>().is_err());
+}
 
-    let mut actual_response_buf = String::with_capacity(expected_response.len());
-    conn.read_line(&mut actual_response_buf).await?;
+#[test]
+fn test_generated_pattern_stays_on_one_key() {
+    let value_sizes = crate::value_size::ValueSize::default().sampler().unwrap();
+    let mut state = BasicState::new();
+    for pattern in ["GET-GET", "SET-GET-SET-DEL", "DEL-SET-GET"] {
+        let generated = BasicPattern::new(
+            &pattern.parse().unwrap(),
+            10,
+            &value_sizes,
+            None,
+            None,
+            true,
+            &mut state,
+        )
+        .unwrap();
+        let key = generated.0[0].key();
+        assert!(
+            generated.0.iter().all(|c| c.key() == key),
+            "{:?}",
+            generated
+        );
+    }
 
-    let duration = start.elapsed();
+    // otherwise every SET starts on a new key
+    let generated = BasicPattern::new(
+        &"SET-GET-SET-DEL".parse().unwrap(),
+        10,
+        &value_sizes,
+        None,
+        None,
+        false,
+        &mut state,
+    )
+    .unwrap();
+    assert_eq!(generated.0[0].key(), generated.0[1].key());
+    assert_ne!(generated.0[1].key(), generated.0[2].key());
+    assert_eq!(generated.0[2].key(), generated.0[3].key());
+}
 
-    PatternExecError::validate_response(expected_response, actual_response_buf)?;
+#[tokio::test]
+async fn test_commands_before_a_lost_connection_are_kept() {
+    let (client, server) = tokio::io::duplex(1024);
+    tokio::spawn(async move {
+        // answers the first command and closes the connection
+        let mut server = BufStream::new(server);
+        let mut line = String::new();
+        server.read_line(&mut line).await.unwrap();
+        server.write_all(b"not found\n").await.unwrap();
+        server.flush().await.unwrap();
+    });
+    let pattern = BasicPattern(
+        vec![
+            "SET k v".parse().unwrap(),
+            "GET k".parse().unwrap(),
+            "DEL k".parse().unwrap(),
+        ],
+        vec!["not found\n".into(), "v\n".into(), "v\n".into()],
+        None,
+    );
+
+    let timing = pattern.execute(&mut BufStream::new(client), None).await;
+    assert!(timing.durations[0].is_ok());
+    assert!(matches!(
+        timing.durations[1..],
+        [
+            Err(PatternExecError::IoError(_)),
+            Err(PatternExecError::IoError(_))
+        ]
+    ));
+    // the DEL was never sent
+    assert_eq!(timing.spans.len(), 2);
+}
 
-    Ok(duration)
+#[inline(always)]
+async fn execute_get<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut BufStream<S>,
+    key: &str,
+    expected_response: String,
+) -> Result<(Duration, Duration), PatternExecError> {
+    execute_line(conn, format!("GET {}\n", key), expected_response).await
 }
 
 #[inline(always)]
-async fn execute_set(
-    conn: &mut BufStream<TcpStream>,
+async fn execute_set<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut BufStream<S>,
     key: String,
     value: String,
     expected_response: String,
-) -> Result<Duration, PatternExecError> {
-    let command_string: String = format!("SET {} {}\n", key, value);
-
-    let start = Instant::now();
-
-    conn.write_all(command_string.as_bytes()).await?;
-    conn.flush().await?;
-
-    let mut actual_response_buf = String::with_capacity(expected_response.len());
-    conn.read_line(&mut actual_response_buf).await?;
-
-    let duration = start.elapsed();
-
-    PatternExecError::validate_response(expected_response, actual_response_buf)?;
-
-    Ok(duration)
+) -> Result<(Duration, Duration), PatternExecError> {
+    execute_line(conn, format!("SET {} {}\n", key, value), expected_response).await
 }
 
 #[inline(always)]
-async fn execute_del(
-    conn: &mut BufStream<TcpStream>,
+async fn execute_del<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut BufStream<S>,
     key: &str,
     expected_response: String,
-) -> Result<Duration, PatternExecError> {
-    let command_string: String = format!("DEL {}\n", key);
+) -> Result<(Duration, Duration), PatternExecError> {
+    execute_line(conn, format!("DEL {}\n", key), expected_response).await
+}
 
+/// Sends the command line and reads the response line, timing the first
+/// byte of the response and the complete response.
+#[inline(always)]
+async fn execute_line<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut BufStream<S>,
+    command_string: String,
+    expected_response: String,
+) -> Result<(Duration, Duration), PatternExecError> {
     let start = Instant::now();
 
     conn.write_all(command_string.as_bytes()).await?;
     conn.flush().await?;
 
+    conn.fill_buf().await?;
+    let first_byte = start.elapsed();
+
     let mut actual_response_buf = String::with_capacity(expected_response.len());
-    conn.read_line(&mut actual_response_buf).await?;
+    if conn.read_line(&mut actual_response_buf).await? == 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
 
     let duration = start.elapsed();
 
     PatternExecError::validate_response(expected_response, actual_response_buf)?;
 
-    Ok(duration)
-}
-
-#[inline]
-pub(crate) fn generate_get(key_len: usize) -> BasicCommand {
-    let key = generate_valid_string(key_len);
-    BasicCommand::Get { key }
-}
-
-#[inline]
-pub(crate) fn generate_del(key_len: usize) -> BasicCommand {
-    let key = generate_valid_string(key_len);
-    BasicCommand::Del { key }
-}
-
-#[inline]
-pub(crate) fn generate_set(key_len: usize, value_len: usize) -> BasicCommand {
-    let key = generate_valid_string(key_len);
-    let value = generate_valid_string(value_len);
-    BasicCommand::Set { key, value }
-}
-
-#[inline(always)]
-pub(crate) fn derive_get(set_command: &BasicCommand) -> BasicCommand {
-    match set_command {
-        BasicCommand::Set { key, .. } => BasicCommand::Get { key: key.clone() },
-        _ => panic!("tried to derive a get command from a non set command"),
-    }
-}
-
-#[inline(always)]
-pub(crate) fn derive_del(set_command: &BasicCommand) -> BasicCommand {
-    match set_command {
-        BasicCommand::Set { key, .. } => BasicCommand::Del { key: key.clone() },
-        _ => panic!("tried to derive a del command from a non set command"),
-    }
+    Ok((first_byte, duration))
 }
 
 #[inline(always)]
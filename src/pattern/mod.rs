@@ -1,15 +1,19 @@
 pub(crate) mod basic;
 
+use std::fmt::Display;
 use std::str::FromStr;
 
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
 use thiserror::Error;
+use tokio::time::Instant;
 
 use self::basic::BasicPattern;
 
 pub type ExecPattern = BasicPattern;
 
 #[derive(Debug, Clone, Copy)]
-pub(crate) enum ParsePatternCommand {
+pub enum ParsePatternCommand {
     GET,
     SET,
     DEL,
@@ -29,22 +33,128 @@ impl FromStr for ParsePatternCommand {
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct ParsePattern(pub(crate) Vec<ParsePatternCommand>);
+pub struct ParsePattern(pub(crate) Vec<ParsePatternCommand>);
 
 impl FromStr for ParsePattern {
     type Err = &'static str;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let inner = s
             .split('-')
-            .map(move |e: &str| -> ParsePatternCommand {
-                ParsePatternCommand::from_str(e).unwrap()
-            })
-            .collect();
+            .map(ParsePatternCommand::from_str)
+            .collect::<Result<_, _>>()?;
 
         Ok(Self(inner))
     }
 }
 
+impl Display for ParsePatternCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::GET => "GET",
+            Self::SET => "SET",
+            Self::DEL => "DEL",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Display for ParsePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, command) in self.0.iter().enumerate() {
+            if idx > 0 {
+                f.write_str("-")?;
+            }
+            write!(f, "{}", command)?;
+        }
+        Ok(())
+    }
+}
+
+/// Several patterns, each drawn with a probability proportional to its
+/// weight. Parsed from comma separated patterns with optional weights, e.g.
+/// `GET:80,SET-GET:15,SET-GET-GET-DEL:5`. A pattern without a weight has
+/// weight 1, so a single pattern is a valid mix.
+#[derive(Debug, Clone)]
+pub struct PatternMix {
+    pub(crate) entries: Vec<(ParsePattern, u32)>,
+    index: WeightedIndex<u32>,
+}
+
+impl PatternMix {
+    pub(crate) fn choose<R: Rng>(&self, rng: &mut R) -> &ParsePattern {
+        &self.entries[self.index.sample(rng)].0
+    }
+}
+
+impl FromStr for PatternMix {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let entries = s
+            .split(',')
+            .map(|entry| match entry.split_once(':') {
+                Some((pattern, weight)) => {
+                    let weight: u32 = weight.parse().map_err(|_| "invalid weight in mix")?;
+                    if weight == 0 {
+                        return Err("weights in a mix must be at least 1");
+                    }
+                    Ok((pattern.parse()?, weight))
+                }
+                None => Ok((entry.parse()?, 1)),
+            })
+            .collect::<Result<Vec<(ParsePattern, u32)>, _>>()?;
+        let index = WeightedIndex::new(entries.iter().map(|(_, weight)| *weight))
+            .map_err(|_| "invalid weights in mix")?;
+
+        Ok(Self { entries, index })
+    }
+}
+
+impl Display for PatternMix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, (pattern, weight)) in self.entries.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}:{}", pattern, weight)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pattern_mix() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mix: PatternMix = "GET:3,SET-GET:1".parse().unwrap();
+    assert_eq!(mix.to_string(), "GET:3,SET-GET:1");
+    assert_eq!(
+        "SET-DEL".parse::<PatternMix>().unwrap().to_string(),
+        "SET-DEL:1"
+    );
+    assert!("GET:0".parse::<PatternMix>().is_err());
+    assert!("GET:x".parse::<PatternMix>().is_err());
+    assert!("PUT:1".parse::<PatternMix>().is_err());
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let gets = (0..4000)
+        .filter(|_| mix.choose(&mut rng).0.len() == 1)
+        .count();
+    assert!(
+        (2700..3300).contains(&gets),
+        "{} of 4000 draws were GET",
+        gets
+    );
+}
+
+/// Interval in which a command was in flight, from just before its request
+/// was written until its response was read completely.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandSpan {
+    pub(crate) invoked: Instant,
+    pub(crate) completed: Instant,
+}
+
 #[derive(Debug, Error)]
 pub enum PatternExecError {
     #[error("io error occured while trying to execute pattern")]
@@ -59,6 +169,15 @@ impl PatternExecError {
         Self::InvalidResponse { expected, found }
     }
 
+    /// Error of a command that was not sent, because the connection failed
+    /// before it.
+    pub(crate) fn not_sent() -> Self {
+        Self::IoError(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "not sent, the connection failed before",
+        ))
+    }
+
     #[inline(always)]
     pub(crate) fn validate_response(expected: String, found: String) -> Result<(), Self> {
         if expected != found {
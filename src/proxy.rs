@@ -0,0 +1,258 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const PROXY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Faults the proxy injects into every connection it forwards.
+///
+/// Rates are probabilities in `[0, 1]` that are evaluated for every chunk
+/// of data read from either side of a connection.
+#[derive(Debug, Clone)]
+pub struct FaultConfig {
+    /// fixed delay added before a chunk is forwarded
+    pub latency: Duration,
+    /// upper bound of the random delay added on top of `latency`
+    pub jitter: Duration,
+    /// maximum throughput per direction in bytes per second
+    pub bandwidth: Option<u64>,
+    /// forward every chunk one byte at a time
+    pub fragment: bool,
+    /// probability of closing both sides of the connection gracefully
+    pub drop_rate: f64,
+    /// probability of aborting the connection with a reset
+    pub reset_rate: f64,
+}
+
+impl FaultConfig {
+    #[inline]
+    fn delay(&self, rng: &mut StdRng) -> Duration {
+        if self.jitter.is_zero() {
+            return self.latency;
+        }
+        let jitter_nanos = rng.gen_range(0..=self.jitter.as_nanos() as u64);
+        self.latency + Duration::from_nanos(jitter_nanos)
+    }
+
+    #[inline]
+    fn throttle(&self, bytes: usize) -> Option<Duration> {
+        self.bandwidth
+            .map(|bw| Duration::from_secs_f64(bytes as f64 / bw.max(1) as f64))
+    }
+
+    async fn forward<W: AsyncWrite + Unpin>(
+        &self,
+        dst: &mut W,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        if self.fragment {
+            for byte in data {
+                dst.write_all(std::slice::from_ref(byte)).await?;
+                dst.flush().await?;
+                if let Some(pause) = self.throttle(1) {
+                    tokio::time::sleep(pause).await;
+                }
+            }
+            return Ok(());
+        }
+
+        dst.write_all(data).await?;
+        dst.flush().await?;
+        if let Some(pause) = self.throttle(data.len()) {
+            tokio::time::sleep(pause).await;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PumpOutcome {
+    Eof,
+    Drop,
+    Reset,
+}
+
+pub(crate) fn parse_probability(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("{} is not a probability between 0 and 1", value));
+    }
+    Ok(value)
+}
+
+pub async fn run_proxy(
+    listen: SocketAddr,
+    upstream: SocketAddr,
+    faults: FaultConfig,
+    seed: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = TcpListener::bind(listen).await?;
+    println!(
+        "proxying {} -> {} with {:?}",
+        listener.local_addr()?,
+        upstream,
+        faults
+    );
+    serve_proxy(listener, upstream, Arc::new(faults), seed).await
+}
+
+pub(crate) async fn serve_proxy(
+    listener: TcpListener,
+    upstream: SocketAddr,
+    faults: Arc<FaultConfig>,
+    seed: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    loop {
+        let (client, _) = listener.accept().await?;
+        let local_faults = faults.clone();
+        let connection_seed = rng.gen();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(client, upstream, local_faults, connection_seed).await
+            {
+                println!("proxy connection failed => {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut client: TcpStream,
+    upstream: SocketAddr,
+    faults: Arc<FaultConfig>,
+    seed: u64,
+) -> std::io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+    client.set_nodelay(true)?;
+    server.set_nodelay(true)?;
+
+    let mut upstream_rng = StdRng::seed_from_u64(seed);
+    let mut downstream_rng = StdRng::seed_from_u64(seed.wrapping_add(1));
+
+    let outcome = {
+        let (mut client_read, mut client_write) = client.split();
+        let (mut server_read, mut server_write) = server.split();
+
+        let upstream_pump = pump(
+            &mut client_read,
+            &mut server_write,
+            &faults,
+            &mut upstream_rng,
+        );
+        let downstream_pump = pump(
+            &mut server_read,
+            &mut client_write,
+            &faults,
+            &mut downstream_rng,
+        );
+        tokio::pin!(upstream_pump);
+        tokio::pin!(downstream_pump);
+
+        let first = tokio::select! {
+            o = &mut upstream_pump => (o, true),
+            o = &mut downstream_pump => (o, false),
+        };
+        match first {
+            (Ok(PumpOutcome::Eof), upstream_first) => {
+                if upstream_first {
+                    downstream_pump.await
+                } else {
+                    upstream_pump.await
+                }
+            }
+            (o, _) => o,
+        }
+    };
+
+    match outcome {
+        Ok(PumpOutcome::Reset) => {
+            client.set_linger(Some(Duration::ZERO))?;
+            server.set_linger(Some(Duration::ZERO))?;
+        }
+        Ok(PumpOutcome::Drop) => {
+            let _ = client.shutdown().await;
+            let _ = server.shutdown().await;
+        }
+        Ok(PumpOutcome::Eof) | Err(_) => {}
+    }
+
+    Ok(())
+}
+
+async fn pump<R, W>(
+    src: &mut R,
+    dst: &mut W,
+    faults: &FaultConfig,
+    rng: &mut StdRng,
+) -> std::io::Result<PumpOutcome>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; PROXY_BUFFER_SIZE];
+    loop {
+        let read = src.read(&mut buf).await?;
+        if read == 0 {
+            // the peer might already be gone, there is nothing left to tell it then
+            let _ = dst.shutdown().await;
+            return Ok(PumpOutcome::Eof);
+        }
+
+        if rng.gen_bool(faults.reset_rate) {
+            return Ok(PumpOutcome::Reset);
+        }
+        if rng.gen_bool(faults.drop_rate) {
+            return Ok(PumpOutcome::Drop);
+        }
+
+        let delay = faults.delay(rng);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        faults.forward(dst, &buf[..read]).await?;
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_forwards_fragmented() {
+    use tokio::io::{AsyncBufReadExt, BufStream};
+
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (conn, _) = echo.accept().await.unwrap();
+        let mut conn = BufStream::new(conn);
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        conn.write_all(line.as_bytes()).await.unwrap();
+        conn.flush().await.unwrap();
+    });
+
+    let faults = FaultConfig {
+        latency: Duration::from_millis(1),
+        jitter: Duration::from_millis(1),
+        bandwidth: None,
+        fragment: true,
+        drop_rate: 0.0,
+        reset_rate: 0.0,
+    };
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(serve_proxy(proxy, echo_addr, Arc::new(faults), Some(7)));
+
+    let mut conn = BufStream::new(TcpStream::connect(proxy_addr).await.unwrap());
+    conn.write_all(b"GET key\n").await.unwrap();
+    conn.flush().await.unwrap();
+    let mut response = String::new();
+    conn.read_line(&mut response).await.unwrap();
+    assert_eq!(response, "GET key\n");
+}
@@ -1,68 +1,94 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeMap, time::Duration};
 
-use crate::pattern::{ExecPattern, PatternExecError};
+use crate::pattern::ExecPattern;
+use crate::supplier::{PatternResponse, TimeResult};
+use comfy_table::Table;
 use tokio::time::Instant;
 
-pub struct ResultEntry {
-    pub pattern: Arc<ExecPattern>,
-    pub durations: Vec<Result<Duration, PatternExecError>>,
-    pub total_duration: Duration,
-    pub start_time: Instant,
+pub(crate) struct ResultEntry<'a> {
+    pub(crate) pattern: &'a ExecPattern,
+    pub(crate) timing: &'a TimeResult,
+    pub(crate) host: &'a str,
 }
 
 const NO_ERROR_STR: &str = "-";
 const NO_DUR_STR: &str = "-";
 
-impl ResultEntry {
+impl ResultEntry<'_> {
+    /// Header of the results file, naming the fixed columns and the columns
+    /// of the first `commands` commands.
+    pub(crate) fn csv_header(commands: usize) -> String {
+        let mut columns = vec![
+            "total_ns".to_string(),
+            "start_ns".to_string(),
+            "handshake_ns".to_string(),
+            "host".to_string(),
+            "connect_ns".to_string(),
+            "close_ns".to_string(),
+        ];
+        for i in 1..=commands {
+            columns.push(format!("command_{}", i));
+            columns.push(format!("duration_{}_ns", i));
+            columns.push(format!("error_{}", i));
+            columns.push(format!("first_byte_{}_ns", i));
+        }
+        columns.join(",")
+    }
+
+    /// Columns are the total duration, the start time, the handshake
+    /// duration, the host and the connect and close durations, followed by
+    /// the command, its duration, error and first byte duration per command,
+    /// see [`ResultEntry::csv_header`].
     pub(crate) fn to_csv_line(&self, global_start_time: Instant) -> String {
         self.to_string_vec(global_start_time).join(",")
     }
 
     #[inline]
     fn to_string_vec(&self, global_start_time: Instant) -> Vec<String> {
-        let mut ret = Vec::with_capacity(self.pattern.0.len() + (self.durations.len() * 2) + 2);
-        self.pattern_to_string_vec(&mut ret);
-        self.durations_to_string_vec(&mut ret);
+        let mut ret = Vec::with_capacity(self.pattern.0.len() * 4 + 6);
         self.total_duration_to_string_vec(&mut ret);
         self.start_time_to_string_vec(&mut ret, global_start_time);
+        push_optional_duration(&mut ret, self.timing.handshake_duration);
+        ret.push(csv_field(self.host));
+        push_optional_duration(&mut ret, self.timing.connect_duration);
+        push_optional_duration(&mut ret, self.timing.close_duration);
+        self.commands_to_string_vec(&mut ret);
         ret
     }
 
     #[inline]
-    fn pattern_to_string_vec(&self, parts: &mut Vec<String>) {
-        self.pattern
-            .0
+    fn commands_to_string_vec(&self, parts: &mut Vec<String>) {
+        let timings = self
+            .timing
+            .durations
             .iter()
-            .for_each(|e| parts.push(e.to_string()))
-    }
-
-    #[inline]
-    fn durations_to_string_vec(&self, parts: &mut Vec<String>) {
-        for i in self.durations.iter() {
-            match i {
+            .zip(self.timing.first_byte_durations.iter());
+        for (command, (duration, first_byte)) in self.pattern.0.iter().zip(timings) {
+            parts.push(csv_field(&command.to_string()));
+            match duration {
                 Ok(d) => {
-                    let duration_string = d.as_nanos().to_string();
-                    parts.push(duration_string);
+                    parts.push(d.as_nanos().to_string());
                     parts.push(NO_ERROR_STR.to_string());
                 }
                 Err(e) => {
-                    let error_string = e.to_string();
                     parts.push(NO_DUR_STR.to_string());
-                    parts.push(error_string);
+                    parts.push(csv_field(&e.to_string()));
                 }
             }
+            push_optional_duration(parts, *first_byte);
         }
     }
 
     #[inline]
     fn total_duration_to_string_vec(&self, parts: &mut Vec<String>) {
-        let duration_string = self.total_duration.as_nanos().to_string();
+        let duration_string = self.timing.total_duration.as_nanos().to_string();
         parts.push(duration_string);
     }
 
     #[inline]
     fn start_time_to_string_vec(&self, parts: &mut Vec<String>, global_start_time: Instant) {
         let start_time_string = self
+            .timing
             .start_time
             .duration_since(global_start_time)
             .as_nanos()
@@ -70,3 +96,257 @@ impl ResultEntry {
         parts.push(start_time_string);
     }
 }
+
+/// Quotes a field holding a comma, a quote or a line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[inline]
+fn push_optional_duration(parts: &mut Vec<String>, duration: Option<Duration>) {
+    match duration {
+        Some(d) => parts.push(d.as_nanos().to_string()),
+        None => parts.push(NO_DUR_STR.to_string()),
+    }
+}
+
+/// Latencies of one kind, e.g. of all commands or all connects of a group of
+/// results.
+#[derive(Debug, Default)]
+pub struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Latency below which `q` (in `[0, 1]`) of the latencies are.
+    pub fn percentile(&mut self, q: f64) -> Option<Duration> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.0.sort_unstable();
+        let idx = ((self.0.len() - 1) as f64 * q).round() as usize;
+        self.0.get(idx).copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let total: u128 = self.0.iter().map(Duration::as_nanos).sum();
+        let mean = total / self.0.len() as u128;
+        Some(Duration::from_nanos(mean as u64))
+    }
+
+    fn push(&mut self, latency: Duration) {
+        self.0.push(latency);
+    }
+
+    /// Mean and percentiles, formatted for a table.
+    fn table_cells(&mut self) -> Vec<String> {
+        let fmt =
+            |d: Option<Duration>| d.map_or_else(|| NO_DUR_STR.to_string(), |d| format!("{:?}", d));
+        vec![
+            fmt(self.mean()),
+            fmt(self.percentile(0.5)),
+            fmt(self.percentile(0.9)),
+            fmt(self.percentile(0.99)),
+            fmt(self.percentile(1.0)),
+        ]
+    }
+}
+
+/// Aggregated latencies of a group of results, e.g. all patterns executed
+/// against the same host.
+#[derive(Debug, Default)]
+pub struct Summary {
+    patterns: usize,
+    errors: usize,
+    latencies: Latencies,
+    connect: Latencies,
+    first_byte: Latencies,
+    close: Latencies,
+}
+
+impl Summary {
+    /// Number of patterns summarized.
+    pub fn patterns(&self) -> usize {
+        self.patterns
+    }
+
+    /// Number of commands summarized, including the failed ones.
+    pub fn commands(&self) -> usize {
+        self.latencies.len() + self.errors
+    }
+
+    /// Number of commands that failed or whose response didn't match the
+    /// prediction.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    pub(crate) fn add(&mut self, timing: &TimeResult) {
+        self.patterns += 1;
+        for d in timing.durations.iter() {
+            match d {
+                Ok(d) => self.latencies.push(*d),
+                Err(_) => self.errors += 1,
+            }
+        }
+        for first_byte in timing.first_byte_durations.iter().flatten() {
+            self.first_byte.push(*first_byte);
+        }
+        if let Some(connect) = timing.connect_duration {
+            self.connect.push(connect);
+        }
+        if let Some(close) = timing.close_duration {
+            self.close.push(close);
+        }
+    }
+
+    /// Latency below which `q` (in `[0, 1]`) of the successful commands are.
+    pub fn percentile(&mut self, q: f64) -> Option<Duration> {
+        self.latencies.percentile(q)
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        self.latencies.mean()
+    }
+
+    /// Durations of establishing connections.
+    pub fn connect(&mut self) -> &mut Latencies {
+        &mut self.connect
+    }
+
+    /// Time from sending a command until the first byte of its response
+    /// arrived, for successful commands.
+    pub fn first_byte(&mut self) -> &mut Latencies {
+        &mut self.first_byte
+    }
+
+    /// Durations of shutting connections down.
+    pub fn close(&mut self) -> &mut Latencies {
+        &mut self.close
+    }
+
+    fn table_row(&mut self, name: &str) -> Vec<String> {
+        let mut row = vec![
+            name.to_string(),
+            self.patterns.to_string(),
+            self.commands().to_string(),
+            self.errors.to_string(),
+        ];
+        row.extend(self.latencies.table_cells());
+        row
+    }
+
+    /// One row per phase of connection handling with any samples.
+    fn phase_rows(&mut self, name: &str) -> Vec<Vec<String>> {
+        [
+            ("connect", &mut self.connect),
+            ("first byte", &mut self.first_byte),
+            ("close", &mut self.close),
+        ]
+        .into_iter()
+        .filter(|(_, latencies)| !latencies.is_empty())
+        .map(|(phase, latencies)| {
+            let mut row = vec![
+                name.to_string(),
+                phase.to_string(),
+                latencies.len().to_string(),
+            ];
+            row.extend(latencies.table_cells());
+            row
+        })
+        .collect()
+    }
+}
+
+/// Summaries of the responses grouped by pattern class, sorted by class.
+pub(crate) fn summaries_by_class<'a>(
+    responses: impl IntoIterator<Item = &'a PatternResponse>,
+) -> Vec<(String, Summary)> {
+    let mut classes: BTreeMap<String, Summary> = BTreeMap::new();
+    for response in responses {
+        classes
+            .entry(response.pattern.class())
+            .or_default()
+            .add(&response.timing);
+    }
+    classes.into_iter().collect()
+}
+
+/// Prints one row per summary, `group` names what the summaries are grouped
+/// by, followed by the latencies of connecting, the first response byte and
+/// closing.
+pub(crate) fn print_summaries(group: &str, summaries: &mut [(String, Summary)]) {
+    let mut table = Table::new();
+    table.set_header(vec![
+        group, "patterns", "commands", "errors", "mean", "p50", "p90", "p99", "max",
+    ]);
+    let mut phases = Table::new();
+    phases.set_header(vec![
+        group, "phase", "samples", "mean", "p50", "p90", "p99", "max",
+    ]);
+    let mut has_phases = false;
+    for (name, summary) in summaries.iter_mut() {
+        table.add_row(summary.table_row(name));
+        for row in summary.phase_rows(name) {
+            phases.add_row(row);
+            has_phases = true;
+        }
+    }
+    println!("{table}");
+    if has_phases {
+        println!("{phases}");
+    }
+}
+
+#[test]
+fn test_csv_line_has_fixed_columns_first() {
+    use crate::pattern::basic::BasicPattern;
+    use crate::pattern::PatternExecError;
+
+    let start = Instant::now();
+    let pattern = BasicPattern(
+        vec!["GET a".parse().unwrap(), "DEL a".parse().unwrap()],
+        vec![],
+        None,
+    );
+    let timing = TimeResult {
+        durations: vec![
+            Ok(Duration::from_nanos(10)),
+            Err(PatternExecError::invalid_response("1".into(), "a,b".into())),
+        ],
+        first_byte_durations: vec![Some(Duration::from_nanos(5)), None],
+        spans: vec![],
+        total_duration: Duration::from_nanos(40),
+        start_time: start,
+        handshake_duration: None,
+        connect_duration: Some(Duration::from_nanos(7)),
+        close_duration: None,
+    };
+    let entry = ResultEntry {
+        pattern: &pattern,
+        timing: &timing,
+        host: "host",
+    };
+
+    assert_eq!(
+        entry.to_csv_line(start),
+        r#"40,0,-,host,7,-,GET a,10,-,5,DEL a,-,"invalid response (expected ""1"", found ""a,b"")",-"#
+    );
+    assert_eq!(
+        ResultEntry::csv_header(1),
+        "total_ns,start_ns,handshake_ns,host,connect_ns,close_ns,command_1,duration_1_ns,error_1,first_byte_1_ns"
+    );
+}
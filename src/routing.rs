@@ -0,0 +1,147 @@
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Target;
+use crate::pattern::{basic::BasicCommand, ExecPattern};
+
+/// Number of points every host occupies on the consistent hashing ring.
+const VIRTUAL_NODES: usize = 128;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a followed by the murmur3 finalizer, used wherever keys have to be
+/// mapped to something in a way that is stable across runs and builds. The
+/// finalizer spreads short, similar keys over the whole range.
+#[inline]
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Routing {
+    /// patterns are distributed evenly over the hosts
+    RoundRobin,
+    /// patterns are sent to the host owning their key on a consistent
+    /// hashing ring
+    Hash,
+}
+
+/// Decides which of several hosts a pattern is executed against.
+pub(crate) struct Router {
+    routing: Routing,
+    hosts: usize,
+    next: usize,
+    ring: Vec<(u64, usize)>,
+}
+
+impl Router {
+    pub(crate) fn new(routing: Routing, targets: &[Target]) -> Self {
+        let mut ring = Vec::new();
+        if routing == Routing::Hash {
+            ring.reserve(targets.len() * VIRTUAL_NODES);
+            for (idx, target) in targets.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES {
+                    let point = stable_hash(format!("{}-{}", target, node).as_bytes());
+                    ring.push((point, idx));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Self {
+            routing,
+            hosts: targets.len(),
+            next: 0,
+            ring,
+        }
+    }
+
+    /// Index of the host the pattern should be executed against. With hash
+    /// routing every key of the pattern has to be owned by that host, as the
+    /// whole pattern is executed on one connection.
+    pub(crate) fn route(&mut self, pattern: &ExecPattern) -> Result<usize, String> {
+        if self.hosts <= 1 {
+            return Ok(0);
+        }
+
+        let mut keys = pattern.0.iter().map(BasicCommand::key);
+        match (self.routing, keys.next()) {
+            (Routing::Hash, Some(key)) => {
+                let owner = self.owner(key);
+                if let Some(key) = keys.find(|key| self.owner(key) != owner) {
+                    return Err(format!(
+                        "the keys {:?} and {:?} of a {} pattern are owned by different hosts, hash routing needs every pattern to stay on one host",
+                        pattern.0[0].key(),
+                        key,
+                        pattern.class()
+                    ));
+                }
+                Ok(owner)
+            }
+            _ => {
+                let ret = self.next;
+                self.next = (self.next + 1) % self.hosts;
+                Ok(ret)
+            }
+        }
+    }
+
+    /// Host owning the key on the consistent hashing ring.
+    fn owner(&self, key: &str) -> usize {
+        let hash = stable_hash(key.as_bytes());
+        let pos = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[pos % self.ring.len()].1
+    }
+}
+
+#[test]
+fn test_hash_routing_is_stable() {
+    use crate::pattern::basic::BasicPattern;
+
+    let targets: Vec<Target> = ["127.0.0.1:8080", "127.0.0.1:8081", "127.0.0.1:8082"]
+        .iter()
+        .map(|t| t.parse().unwrap())
+        .collect();
+    let pattern = |keys: &[&str]| {
+        BasicPattern(
+            keys.iter()
+                .map(|key| BasicCommand::Get {
+                    key: key.to_string(),
+                })
+                .collect(),
+            vec!["not found\n".to_string(); keys.len()],
+            None,
+        )
+    };
+
+    let mut router = Router::new(Routing::Hash, &targets);
+    let mut hits = vec![0usize; targets.len()];
+    for i in 0..3000 {
+        let key = format!("key{}", i);
+        let first = router.route(&pattern(&[&key])).unwrap();
+        assert_eq!(first, router.route(&pattern(&[&key, &key])).unwrap());
+        hits[first] += 1;
+    }
+    assert!(hits.iter().all(|h| *h > 500), "unbalanced ring {:?}", hits);
+
+    let split = (1..)
+        .map(|i| format!("key{}", i))
+        .find(|key| router.owner(key) != router.owner("key0"))
+        .unwrap();
+    assert!(router.route(&pattern(&["key0", &split])).is_err());
+
+    let mut round_robin = Router::new(Routing::RoundRobin, &targets);
+    let routed: Vec<usize> = (0..4)
+        .map(|_| round_robin.route(&pattern(&["a", "b"])).unwrap())
+        .collect();
+    assert_eq!(routed, vec![0, 1, 2, 0]);
+}
@@ -0,0 +1,519 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::autotune::Slo;
+use crate::benchmark::BenchmarkConfig;
+use crate::connection::Target;
+use crate::datafile::PatternRange;
+use crate::generator::GenerateConfig;
+use crate::options::{BenchmarkArgs, TlsArgs};
+use crate::pattern::PatternMix;
+use crate::routing::Routing;
+use crate::think_time::{ThinkTime, ThinkTimes};
+use crate::value_size::ValueSize;
+use crate::worker::ConnectionMode;
+
+const DEFAULT_DATA_FILE: &str = "data.bin";
+const DEFAULT_OUTPUT_FILE: &str = "result.csv";
+const DEFAULT_TARGET: &str = "127.0.0.1:8080";
+
+#[derive(Debug, Error)]
+pub enum ScenarioError {
+    #[error("couldn't read scenario file {path:?} => {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid scenario file {path:?} => {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid value for `{key}` => {message}")]
+    Invalid { key: &'static str, message: String },
+}
+
+impl ScenarioError {
+    fn invalid(key: &'static str, message: impl ToString) -> Self {
+        Self::Invalid {
+            key,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A benchmark described in a TOML file, so it can be versioned and rerun.
+///
+/// ```toml
+/// duration = "30s"
+/// warmup = "5s"
+/// concurrency = 16
+/// connection = "persistent"
+/// think_time = "uniform:1ms..5ms"
+/// key_prefix = "bench:"
+/// salt = true
+/// cleanup = true
+/// targets = ["127.0.0.1:8080", "unix:/tmp/server.sock"]
+/// server_pids = [4242]
+/// auto_tune = "p99<5ms"
+///
+/// [data]
+/// file = "data.bin"
+/// in_memory = true
+/// pattern_range = "0..50000"
+///
+/// [data.generate]
+/// size = 100000
+/// pattern = "GET:80,SET-GET:15,SET-GET-GET-DEL:5"
+///
+/// [output]
+/// file = "result.csv"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Scenario {
+    duration: Option<String>,
+    warmup: Option<String>,
+    concurrency: Option<usize>,
+    connection: Option<ConnectionMode>,
+    targets: Option<Vec<String>>,
+    routing: Option<Routing>,
+    check_linearizability: Option<bool>,
+    think_time: Option<String>,
+    pattern_think_time: Option<String>,
+    key_prefix: Option<String>,
+    salt: Option<bool>,
+    cleanup: Option<bool>,
+    server_pids: Option<Vec<u32>>,
+    auto_tune: Option<String>,
+    data: Option<DataSection>,
+    output: Option<OutputSection>,
+    tls: Option<TlsSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DataSection {
+    file: Option<PathBuf>,
+    /// decode the data file into memory before the benchmark starts
+    in_memory: Option<bool>,
+    /// positions of the patterns that are replayed, e.g. `0..50000`
+    pattern_range: Option<String>,
+    /// generate the data file before the benchmark starts
+    generate: Option<GenerateSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GenerateSection {
+    size: usize,
+    pattern: Option<String>,
+    key_size: Option<usize>,
+    /// a number of characters or a distribution, see `ValueSize`
+    value_size: Option<SizeSpec>,
+    compression_level: Option<i32>,
+    partitions: Option<u32>,
+    think_time: Option<String>,
+    pattern_think_time: Option<String>,
+    preload: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SizeSpec {
+    Fixed(usize),
+    Distribution(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputSection {
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsSection {
+    enabled: Option<bool>,
+    ca: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    sni: Option<String>,
+}
+
+impl Scenario {
+    pub(crate) fn load(path: &Path) -> Result<Self, ScenarioError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ScenarioError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&content).map_err(|source| ScenarioError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// Merges the command line arguments with the scenario file they point to.
+/// Arguments given on the command line take precedence.
+pub fn benchmark_config(args: BenchmarkArgs) -> Result<BenchmarkConfig, ScenarioError> {
+    let scenario = match &args.scenario {
+        Some(path) => Scenario::load(path)?,
+        None => Scenario::default(),
+    };
+    resolve(args, scenario)
+}
+
+fn resolve(args: BenchmarkArgs, scenario: Scenario) -> Result<BenchmarkConfig, ScenarioError> {
+    let duration = match args.duration {
+        Some(duration) => duration,
+        None => match &scenario.duration {
+            Some(duration) => parse_duration_key("duration", duration)?,
+            None => {
+                return Err(ScenarioError::invalid(
+                    "duration",
+                    "missing, pass it on the command line or set it in the scenario",
+                ))
+            }
+        },
+    };
+    if duration.is_zero() {
+        return Err(ScenarioError::invalid("duration", "must be longer than 0s"));
+    }
+
+    let warmup = match (args.warmup, &scenario.warmup) {
+        (Some(warmup), _) => warmup,
+        (None, Some(warmup)) => parse_duration_key("warmup", warmup)?,
+        (None, None) => Duration::ZERO,
+    };
+
+    let concurrency = args.concurrency.or(scenario.concurrency);
+    if concurrency == Some(0) {
+        return Err(ScenarioError::invalid("concurrency", "must be at least 1"));
+    }
+
+    let targets = if !args.hosts.is_empty() {
+        args.hosts
+    } else {
+        let targets = scenario
+            .targets
+            .unwrap_or_else(|| vec![DEFAULT_TARGET.to_string()]);
+        if targets.is_empty() {
+            return Err(ScenarioError::invalid("targets", "needs at least one host"));
+        }
+        targets
+            .iter()
+            .map(|t| t.parse().map_err(|e| ScenarioError::invalid("targets", e)))
+            .collect::<Result<Vec<Target>, _>>()?
+    };
+
+    let data = scenario.data.unwrap_or_default();
+    let inp_file = args
+        .inp_file
+        .or(data.file)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_DATA_FILE));
+    let in_memory = flag(args.in_memory, args.no_in_memory, data.in_memory);
+    let pattern_range = match args.pattern_range {
+        Some(range) => Some(range),
+        None => data
+            .pattern_range
+            .map(|range| range.parse::<PatternRange>())
+            .transpose()
+            .map_err(|e| ScenarioError::invalid("data.pattern_range", e))?,
+    };
+    let generate = data
+        .generate
+        .map(|section| generate_config(section, inp_file.clone()))
+        .transpose()?;
+
+    let out_file = args
+        .out_file
+        .or_else(|| scenario.output.and_then(|o| o.file))
+        .or_else(|| Some(PathBuf::from(DEFAULT_OUTPUT_FILE)));
+
+    let think_times = ThinkTimes {
+        command: args
+            .think_time
+            .map(Ok)
+            .or_else(|| parse_think_time_key("think_time", &scenario.think_time))
+            .transpose()?,
+        pattern: args
+            .pattern_think_time
+            .map(Ok)
+            .or_else(|| parse_think_time_key("pattern_think_time", &scenario.pattern_think_time))
+            .transpose()?,
+    };
+
+    let key_prefix = args.key_prefix.or(scenario.key_prefix);
+    if key_prefix
+        .as_deref()
+        .is_some_and(|p| p.contains(char::is_whitespace))
+    {
+        return Err(ScenarioError::invalid(
+            "key_prefix",
+            "keys can't contain whitespace",
+        ));
+    }
+
+    let auto_tune = match args.auto_tune {
+        Some(slo) => Some(slo),
+        None => scenario
+            .auto_tune
+            .map(|slo| slo.parse::<Slo>())
+            .transpose()
+            .map_err(|e| ScenarioError::invalid("auto_tune", e))?,
+    };
+
+    let tls = tls_args(args.tls, args.no_tls, scenario.tls)?;
+
+    Ok(BenchmarkConfig {
+        duration,
+        warmup,
+        concurrency,
+        connection: args
+            .connection
+            .or(scenario.connection)
+            .unwrap_or(ConnectionMode::PerPattern),
+        inp_file,
+        in_memory,
+        pattern_range,
+        generate,
+        out_file,
+        targets,
+        routing: args
+            .routing
+            .or(scenario.routing)
+            .unwrap_or(Routing::RoundRobin),
+        check_linearizability: flag(
+            args.check_linearizability,
+            args.no_check_linearizability,
+            scenario.check_linearizability,
+        ),
+        think_times,
+        key_prefix,
+        salt: flag(args.salt, args.no_salt, scenario.salt),
+        cleanup: flag(args.cleanup, args.no_cleanup, scenario.cleanup),
+        server_pids: if args.server_pids.is_empty() {
+            scenario.server_pids.unwrap_or_default()
+        } else {
+            args.server_pids
+        },
+        auto_tune,
+        tls,
+        fd_limit: None,
+    })
+}
+
+fn generate_config(
+    section: GenerateSection,
+    data_out: PathBuf,
+) -> Result<GenerateConfig, ScenarioError> {
+    if section.size == 0 {
+        return Err(ScenarioError::invalid(
+            "data.generate.size",
+            "must be at least 1",
+        ));
+    }
+    let pattern: PatternMix = section
+        .pattern
+        .as_deref()
+        .unwrap_or("SET-GET-GET-DEL")
+        .parse()
+        .map_err(|e| ScenarioError::invalid("data.generate.pattern", e))?;
+    let key_size = section.key_size.unwrap_or(10);
+    if key_size == 0 {
+        return Err(ScenarioError::invalid(
+            "data.generate.key_size",
+            "must be at least 1",
+        ));
+    }
+    let compression_level = section.compression_level.unwrap_or(0);
+    if !(0..=21).contains(&compression_level) {
+        return Err(ScenarioError::invalid(
+            "data.generate.compression_level",
+            "must be between 0 and 21",
+        ));
+    }
+
+    Ok(GenerateConfig {
+        size: section.size,
+        data_out,
+        pattern,
+        key_size,
+        value_size: match section.value_size {
+            None => ValueSize::default(),
+            Some(SizeSpec::Fixed(size)) => size
+                .to_string()
+                .parse()
+                .map_err(|e| ScenarioError::invalid("data.generate.value_size", e))?,
+            Some(SizeSpec::Distribution(spec)) => spec
+                .parse()
+                .map_err(|e| ScenarioError::invalid("data.generate.value_size", e))?,
+        },
+        compression_level,
+        partitions: section.partitions.unwrap_or(0),
+        routing: Routing::RoundRobin,
+        think_times: ThinkTimes {
+            command: parse_think_time_key("data.generate.think_time", &section.think_time)
+                .transpose()?,
+            pattern: parse_think_time_key(
+                "data.generate.pattern_think_time",
+                &section.pattern_think_time,
+            )
+            .transpose()?,
+        },
+        preload: section.preload.unwrap_or(0),
+    })
+}
+
+/// A switch turned on by `--name`, off by `--no-name` and taken from the
+/// scenario if neither is given.
+fn flag(on: bool, off: bool, scenario: Option<bool>) -> bool {
+    match (on, off) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => scenario.unwrap_or(false),
+    }
+}
+
+/// Merges the TLS options key by key, the ones given on the command line
+/// replace their counterparts in the scenario.
+fn tls_args(
+    args: TlsArgs,
+    no_tls: bool,
+    section: Option<TlsSection>,
+) -> Result<TlsArgs, ScenarioError> {
+    let enabled = section.is_some();
+    let section = section.unwrap_or_default();
+    if section.cert.is_some() != section.key.is_some() {
+        return Err(ScenarioError::invalid(
+            "tls.cert",
+            "a client certificate needs both `tls.cert` and `tls.key`",
+        ));
+    }
+    // the command line only accepts a certificate together with its key
+    let (tls_cert, tls_key) = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => (Some(cert), Some(key)),
+        _ => (section.cert, section.key),
+    };
+    Ok(TlsArgs {
+        tls: flag(args.tls, no_tls, Some(section.enabled.unwrap_or(enabled))),
+        tls_ca: args.tls_ca.or(section.ca),
+        tls_cert,
+        tls_key,
+        tls_sni: args.tls_sni.or(section.sni),
+    })
+}
+
+fn parse_think_time_key(
+    key: &'static str,
+    value: &Option<String>,
+) -> Option<Result<ThinkTime, ScenarioError>> {
+    value
+        .as_ref()
+        .map(|v| v.parse().map_err(|e| ScenarioError::invalid(key, e)))
+}
+
+fn parse_duration_key(key: &'static str, value: &str) -> Result<Duration, ScenarioError> {
+    parse_duration::parse(value).map_err(|e| ScenarioError::invalid(key, e))
+}
+
+#[cfg(test)]
+fn benchmark_args(args: &[&str]) -> BenchmarkArgs {
+    use crate::options::{Cli, Commands};
+    use clap::Parser;
+
+    let cli = Cli::parse_from(["client", "benchmark"].iter().chain(args));
+    match cli.command {
+        Commands::Benchmark(args) => args,
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_scenario_merges_with_command_line() {
+    let scenario: Scenario = toml::from_str(
+        r#"
+        duration = "10s"
+        warmup = "2s"
+        concurrency = 4
+        connection = "persistent"
+        targets = ["127.0.0.1:9000", "127.0.0.1:9001"]
+
+        [data]
+        file = "scenario.bin"
+
+        [data.generate]
+        size = 100
+        pattern = "SET-GET"
+        value_size = "uniform:10..20"
+        "#,
+    )
+    .unwrap();
+
+    let config = resolve(benchmark_args(&["--concurrency", "8"]), scenario).unwrap();
+    assert_eq!(config.duration, Duration::from_secs(10));
+    assert_eq!(config.warmup, Duration::from_secs(2));
+    assert_eq!(config.concurrency, Some(8));
+    assert_eq!(config.connection, ConnectionMode::Persistent);
+    assert_eq!(config.targets.len(), 2);
+    assert_eq!(config.inp_file, PathBuf::from("scenario.bin"));
+    let generate = config.generate.unwrap();
+    assert_eq!(generate.size, 100);
+    assert_eq!(generate.data_out, PathBuf::from("scenario.bin"));
+    assert_eq!(generate.value_size, ValueSize::Uniform { min: 10, max: 20 });
+    assert_eq!(config.out_file, Some(PathBuf::from(DEFAULT_OUTPUT_FILE)));
+}
+
+#[test]
+fn test_command_line_overrides_scenario_keys() {
+    let scenario = || -> Scenario {
+        toml::from_str(
+            r#"
+            duration = "10s"
+            salt = true
+            cleanup = true
+
+            [tls]
+            ca = "ca.pem"
+            sni = "scenario.example"
+            "#,
+        )
+        .unwrap()
+    };
+
+    let config = resolve(
+        benchmark_args(&["--tls", "--tls-sni", "cli.example"]),
+        scenario(),
+    )
+    .unwrap();
+    assert!(config.tls.tls && config.salt && config.cleanup);
+    assert_eq!(config.tls.tls_ca, Some(PathBuf::from("ca.pem")));
+    assert_eq!(config.tls.tls_sni.as_deref(), Some("cli.example"));
+
+    let config = resolve(benchmark_args(&["--no-tls", "--no-salt"]), scenario()).unwrap();
+    assert!(!config.tls.tls && !config.salt && config.cleanup);
+}
+
+#[test]
+fn test_scenario_errors_name_the_key() {
+    let unknown = toml::from_str::<Scenario>("durration = \"10s\"").unwrap_err();
+    assert!(unknown.to_string().contains("durration"));
+
+    let scenario: Scenario = toml::from_str("duration = \"10s\"\nconcurrency = 0").unwrap();
+    let err = resolve(benchmark_args(&[]), scenario).unwrap_err();
+    assert!(matches!(
+        err,
+        ScenarioError::Invalid {
+            key: "concurrency",
+            ..
+        }
+    ));
+
+    let scenario: Scenario = toml::from_str("duration = \"soon\"").unwrap();
+    let err = resolve(benchmark_args(&[]), scenario).unwrap_err();
+    assert!(err.to_string().contains("`duration`"));
+}
@@ -0,0 +1,216 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Resource usage of a process as reported by `/proc/<pid>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProcStats {
+    /// user and system CPU time used so far
+    cpu_time: Duration,
+    rss_bytes: u64,
+    threads: u64,
+    /// `None` if the descriptors of the process can't be listed, e.g. because
+    /// it belongs to another user
+    fds: Option<usize>,
+}
+
+#[cfg(unix)]
+fn sysconf(name: libc::c_int, fallback: u64) -> u64 {
+    match unsafe { libc::sysconf(name) } {
+        value if value > 0 => value as u64,
+        _ => fallback,
+    }
+}
+
+#[cfg(unix)]
+fn clock_ticks() -> u64 {
+    sysconf(libc::_SC_CLK_TCK, 100)
+}
+
+#[cfg(not(unix))]
+fn clock_ticks() -> u64 {
+    100
+}
+
+#[cfg(unix)]
+fn page_size() -> u64 {
+    sysconf(libc::_SC_PAGESIZE, 4096)
+}
+
+#[cfg(not(unix))]
+fn page_size() -> u64 {
+    4096
+}
+
+/// Parses `/proc/<pid>/stat`, returning the CPU time, RSS and thread count.
+fn parse_stat(stat: &str, ticks: u64, page_size: u64) -> Option<(Duration, u64, u64)> {
+    // the command name is in parentheses and may contain spaces, the fields
+    // after it start with the state, the third field of the file
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    let cpu_ticks = field(14)? + field(15)?;
+    let cpu_time = Duration::from_secs(cpu_ticks / ticks)
+        + Duration::from_nanos(cpu_ticks % ticks * 1_000_000_000 / ticks);
+    Some((cpu_time, field(24)? * page_size, field(20)?))
+}
+
+impl ProcStats {
+    fn read(pid: u32) -> std::io::Result<Self> {
+        let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+        let stat = std::fs::read_to_string(proc_dir.join("stat"))?;
+        let (cpu_time, rss_bytes, threads) = parse_stat(&stat, clock_ticks(), page_size())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unexpected format of /proc/{}/stat", pid),
+                )
+            })?;
+        let fds = std::fs::read_dir(proc_dir.join("fd"))
+            .ok()
+            .map(|dir| dir.count());
+        Ok(Self {
+            cpu_time,
+            rss_bytes,
+            threads,
+            fds,
+        })
+    }
+}
+
+/// Fails early if a server process can't be sampled, rather than after the
+/// benchmark.
+pub(crate) fn check_server_pids(pids: &[u32]) -> Result<(), String> {
+    for &pid in pids {
+        ProcStats::read(pid)
+            .map_err(|e| format!("can't sample server process {} => {}", pid, e))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub(crate) struct ServerSample {
+    /// time since the start of the benchmark
+    elapsed: Duration,
+    pid: u32,
+    /// CPU time used since the previous sample per wall-clock time, 1.0 is
+    /// one core fully used
+    cpu: f64,
+    stats: ProcStats,
+}
+
+/// Samples the server processes until the kill switch fires, processes that
+/// exit are no longer sampled.
+pub(crate) async fn monitor_server(
+    pids: Vec<u32>,
+    start_time: Instant,
+    mut kill_switch: tokio::sync::watch::Receiver<()>,
+) -> Vec<ServerSample> {
+    let mut samples = Vec::new();
+    let mut last: Vec<(u32, Instant, ProcStats)> = pids
+        .into_iter()
+        .filter_map(|pid| Some((pid, Instant::now(), ProcStats::read(pid).ok()?)))
+        .collect();
+
+    while !last.is_empty() {
+        tokio::select! {
+            _ = tokio::time::sleep(SAMPLE_INTERVAL) => {}
+            _ = kill_switch.changed() => break,
+        }
+
+        last.retain_mut(|(pid, last_time, last_stats)| {
+            let stats = match ProcStats::read(*pid) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    println!("stopped sampling server process {} => {}", pid, e);
+                    return false;
+                }
+            };
+            let now = Instant::now();
+            let cpu = stats
+                .cpu_time
+                .saturating_sub(last_stats.cpu_time)
+                .as_secs_f64()
+                / now.duration_since(*last_time).as_secs_f64();
+            samples.push(ServerSample {
+                elapsed: now.duration_since(start_time),
+                pid: *pid,
+                cpu,
+                stats,
+            });
+            *last_time = now;
+            *last_stats = stats;
+            true
+        });
+    }
+
+    samples
+}
+
+/// Path of the server samples belonging to a results file, `result.csv` gets
+/// `result.server.csv`.
+pub(crate) fn samples_path_for(out_file: &Path) -> PathBuf {
+    out_file.with_extension("server.csv")
+}
+
+pub(crate) fn write_server_samples(
+    path: &Path,
+    samples: &[ServerSample],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(out, "elapsed_ms,pid,cpu_time_ms,cpu,rss_bytes,threads,fds")?;
+    for sample in samples {
+        writeln!(
+            out,
+            "{},{},{},{:.3},{},{},{}",
+            sample.elapsed.as_millis(),
+            sample.pid,
+            sample.stats.cpu_time.as_millis(),
+            sample.cpu,
+            sample.stats.rss_bytes,
+            sample.stats.threads,
+            sample
+                .stats
+                .fds
+                .map_or(String::new(), |fds| fds.to_string()),
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints the mean CPU usage and peak memory of every sampled process.
+pub(crate) fn print_server_samples(samples: &[ServerSample]) {
+    let mut pids: Vec<u32> = samples.iter().map(|s| s.pid).collect();
+    pids.sort_unstable();
+    pids.dedup();
+    for pid in pids {
+        let own: Vec<&ServerSample> = samples.iter().filter(|s| s.pid == pid).collect();
+        let cpu = own.iter().map(|s| s.cpu).sum::<f64>() / own.len() as f64;
+        let rss = own.iter().map(|s| s.stats.rss_bytes).max().unwrap_or(0);
+        let threads = own.iter().map(|s| s.stats.threads).max().unwrap_or(0);
+        println!(
+            "server process {} used {:.0}% of a core on average, at most {} KiB resident and {} threads",
+            pid,
+            cpu * 100.0,
+            rss / 1024,
+            threads
+        );
+    }
+}
+
+#[test]
+fn test_parse_stat() {
+    let stat = "4242 (my server) S 1 4242 4242 0 -1 4194560 1510 0 0 0 250 130 0 0 20 0 7 0 \
+                123456 104857600 2048 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 0 0 0 0 0 0";
+    let (cpu_time, rss, threads) = parse_stat(stat, 100, 4096).unwrap();
+    assert_eq!(cpu_time, Duration::from_millis(3800));
+    assert_eq!(rss, 2048 * 4096);
+    assert_eq!(threads, 7);
+
+    assert_eq!(parse_stat("4242 (truncated) S 1", 100, 4096), None);
+}
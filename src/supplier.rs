@@ -1,28 +1,72 @@
 use std::cmp::Ordering;
-
+use std::collections::VecDeque;
 use std::error::Error;
-use std::io::{ErrorKind, SeekFrom};
-use std::path::Path;
+use std::ops::Range;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 use tokio::time::Instant;
 
-use crate::pattern::{ExecPattern, PatternExecError};
+use crate::datafile::DataFile;
+use crate::monitor::ClientCounters;
+use crate::namespace::KeyNamespace;
+use crate::pattern::{CommandSpan, ExecPattern, PatternExecError};
+use crate::routing::Router;
 
 #[derive(Debug)]
-pub(crate) struct TimeResult {
-    pub(crate) durations: Vec<Result<Duration, PatternExecError>>,
-    pub(crate) total_duration: Duration,
-    pub(crate) start_time: Instant,
+pub struct TimeResult {
+    pub durations: Vec<Result<Duration, PatternExecError>>,
+    /// time from sending each command until the first byte of its response
+    /// arrived, `None` for failed commands
+    pub first_byte_durations: Vec<Option<Duration>>,
+    /// when each command was in flight, used to reconstruct histories
+    pub(crate) spans: Vec<CommandSpan>,
+    pub total_duration: Duration,
+    pub start_time: Instant,
+    /// duration of the TLS handshake, if the connection used TLS
+    pub handshake_duration: Option<Duration>,
+    /// duration of establishing the connection, excluding the TLS handshake,
+    /// `None` if an open connection was reused
+    pub connect_duration: Option<Duration>,
+    /// duration of shutting the connection down, `None` if it was kept open
+    pub close_duration: Option<Duration>,
+}
+
+impl TimeResult {
+    /// Result of a pattern none of whose commands could be sent, because
+    /// opening the connection failed with `error`.
+    pub(crate) fn failed(commands: usize, error: std::io::Error, start_time: Instant) -> Self {
+        let durations = std::iter::once(Err(error.into()))
+            .chain((1..commands).map(|_| Err(PatternExecError::not_sent())))
+            .collect();
+        Self {
+            durations,
+            first_byte_durations: vec![None; commands],
+            spans: Vec::new(),
+            total_duration: start_time.elapsed(),
+            start_time,
+            handshake_duration: None,
+            connect_duration: None,
+            close_duration: None,
+        }
+    }
+
+    /// Whether a command failed with an I/O error, which leaves the
+    /// connection unusable.
+    pub(crate) fn io_failed(&self) -> bool {
+        self.durations
+            .iter()
+            .any(|d| matches!(d, Err(PatternExecError::IoError(_))))
+    }
 }
 
 #[derive(Debug)]
-pub(crate) struct PatternResponse {
-    pub(crate) timing: TimeResult,
-    pub(crate) pattern: Arc<ExecPattern>,
+pub struct PatternResponse {
+    pub timing: TimeResult,
+    pub pattern: Arc<ExecPattern>,
+    /// index of the host the pattern was executed against
+    pub target: usize,
 }
 
 impl Eq for PatternResponse {}
@@ -35,7 +79,7 @@ impl PartialEq<Self> for PatternResponse {
 
 impl PartialOrd<Self> for PatternResponse {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        PartialOrd::partial_cmp(&self.timing.start_time, &other.timing.start_time)
+        Some(self.cmp(other))
     }
 }
 
@@ -48,152 +92,174 @@ impl Ord for PatternResponse {
 #[derive(Debug)]
 pub(crate) struct PatternBundle {
     pub(crate) pattern: Arc<ExecPattern>,
+    /// index of the host the pattern is executed against
+    pub(crate) target: usize,
 }
 
-pub(crate) async fn feed_chans<const TEST_MODE: bool>(
-    mut pattern: tokio::sync::mpsc::Receiver<ExecPattern>,
-    worker_chans: Vec<tokio::sync::mpsc::Sender<PatternBundle>>,
-    kill_switch: tokio::sync::watch::Receiver<()>,
+/// Routes the decoded patterns into the queue all workers take from, so idle
+/// workers always pick up the next pattern while busy ones are left alone.
+pub(crate) async fn feed_shared(
+    mut pattern: tokio::sync::mpsc::Receiver<Arc<ExecPattern>>,
+    worker_chan: flume::Sender<PatternBundle>,
+    mut kill_switch: tokio::sync::watch::Receiver<()>,
+    mut router: Router,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // let pat = pattern.recv_async().await?;
-    let pat = tokio::task::unconstrained(pattern.recv()).await.unwrap();
-    // let pat = pattern.recv().await.unwrap();
-    let mut bundle = PatternBundle {
-        pattern: Arc::new(pat),
-    };
-    for (idx, i) in worker_chans.iter().enumerate().cycle() {
-        match i.try_send(bundle) {
-            Ok(()) => {
-                let pat_opt = tokio::task::unconstrained(pattern.recv()).await;
-                let pat = if TEST_MODE && pat_opt.is_none() {
+    while let Some(pat) = pattern.recv().await {
+        let bundle = PatternBundle {
+            target: router.route(&pat)?,
+            pattern: pat,
+        };
+        tokio::select! {
+            sent = worker_chan.send_async(bundle) => {
+                if sent.is_err() {
                     return Ok(());
-                } else {
-                    pat_opt.unwrap()
-                };
-                bundle = PatternBundle {
-                    pattern: Arc::new(pat),
-                };
-            }
-            Err(tokio::sync::mpsc::error::TrySendError::Full(d)) => {
-                bundle = d;
+                }
             }
-            e => {
-                println!("Quitting => {:?}", e);
-                println!("Idx => {}", idx);
+            _ = kill_switch.changed() => {
                 return Ok(());
             }
         }
-
-        if kill_switch.has_changed().unwrap() {
-            return Ok(());
-        }
     }
-    unreachable!()
+    Ok(())
 }
 
-async fn decode_from_file(
-    file: &mut async_compression::tokio::bufread::ZstdDecoder<
-        tokio::io::BufReader<tokio::fs::File>,
-    >,
-    buf: &mut Vec<u8>,
-) -> std::io::Result<ExecPattern> {
-    // println!("{} {}", file.get_mut().get_mut().stream_position().await?, file_length);
-    /*
-    if file.stream_position().await? >= file_length - 1000 {
-        return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "lol"));
+/// Sends every pattern to the worker owning its key partition. Patterns that
+/// may touch the same keys are thereby executed one after another by the same
+/// worker, which keeps the predicted responses valid under concurrency.
+pub(crate) async fn feed_partitioned(
+    mut pattern: tokio::sync::mpsc::Receiver<Arc<ExecPattern>>,
+    worker_chans: Vec<flume::Sender<PatternBundle>>,
+    mut kill_switch: tokio::sync::watch::Receiver<()>,
+    mut router: Router,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    while let Some(pat) = pattern.recv().await {
+        let owner = pat.2.unwrap_or(0) as usize % worker_chans.len();
+        let bundle = PatternBundle {
+            target: router.route(&pat)?,
+            pattern: pat,
+        };
+        tokio::select! {
+            sent = worker_chans[owner].send_async(bundle) => {
+                if sent.is_err() {
+                    return Ok(());
+                }
+            }
+            _ = kill_switch.changed() => {
+                return Ok(());
+            }
+        }
     }
-
-     */
-    let mut length_buffer = [0u8; 8];
-
-    file.read_exact(&mut length_buffer).await?;
-    let length = u64::from_le_bytes(length_buffer);
-    buf.resize(length as usize, 0);
-    file.read_exact(buf).await?;
-    let ret: ExecPattern = bincode::deserialize(buf).expect("error decoding");
-    Ok(ret)
+    Ok(())
 }
 
-pub(crate) async fn feed_from_file<T: AsRef<Path>>(
-    path: T,
-    sender: tokio::sync::mpsc::Sender<ExecPattern>,
+/// Decodes the patterns in `range` of the data file while the benchmark runs,
+/// starting over at the end of the range. As many chunks as there are cores
+/// are decoded in parallel ahead of the one being sent.
+pub(crate) async fn feed_from_file(
+    data_file: DataFile,
+    range: Range<u64>,
+    sender: tokio::sync::mpsc::Sender<Arc<ExecPattern>>,
+    namespace: KeyNamespace,
+    counters: Arc<ClientCounters>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    /*
-    loop {
-        let pattern = ExecPattern {
-            0: vec![BasicCommand::Set { key: "KEY".into(), value: "VAL".into()}]
-        };
-        sender.send(pattern).await?;
-        tokio::time::sleep(Duration::from_millis(1)).await;
+    if range.is_empty() {
+        return Err("the data file contains no patterns".into());
     }
-
-     */
-
-    let file = tokio::fs::File::open(path)
-        .await
-        .expect("error opening file");
-
-    let file_length = file
-        .metadata()
-        .await
-        .expect("error getting metadata from file")
-        .len();
-    let file_buf = tokio::io::BufReader::new(file);
-
-    let mut decoder = async_compression::tokio::bufread::ZstdDecoder::new(file_buf);
-    let mut buf = vec![0u8; 100];
-
-    let mut bytes_position = 0;
+    let chunk_range = data_file.chunk_of(range.start)..=data_file.chunk_of(range.end - 1);
+    // decoding further ahead than the range is long would decode the same
+    // chunks several times at once
+    let decode_ahead = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(chunk_range.clone().count());
+    let mut chunks = chunk_range.cycle();
+    let mut decoding = VecDeque::with_capacity(decode_ahead);
     loop {
-        match decode_from_file(&mut decoder, &mut buf).await {
-            Ok(d) => {
-                sender.send(d).await?;
-            }
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::UnexpectedEof => {
-                        let file_buf = decoder.into_inner();
-                        let mut file = file_buf.into_inner();
-                        file.seek(SeekFrom::Start(0)).await?;
-                        let file_buf = tokio::io::BufReader::new(file);
-                        decoder = async_compression::tokio::bufread::ZstdDecoder::new(file_buf);
-                    }
-                    _ => {
-                        println!("Error in file feeder => {:?}", e);
-                        return Err(Box::new(e));
-                    }
-                }
-            }
+        while decoding.len() < decode_ahead {
+            let chunk = chunks.next().unwrap();
+            let local_data_file = data_file.clone();
+            decoding.push_back((
+                data_file.chunks()[chunk].first_pattern,
+                tokio::spawn(async move { local_data_file.read_chunk(chunk).await }),
+            ));
         }
-        if bytes_position >= file_length - 1000 {
-            println!("refreshing file buffer 2");
-            let file_buf = decoder.into_inner();
-
-            let mut file = file_buf.into_inner();
-            file.seek(SeekFrom::Start(0)).await?;
-            decoder = async_compression::tokio::bufread::ZstdDecoder::new(
-                tokio::io::BufReader::new(file),
-            );
-            bytes_position = 0;
+        let (first, handle) = decoding.pop_front().unwrap();
+        for (position, mut pattern) in (first..).zip(handle.await??) {
+            if !range.contains(&position) {
+                continue;
+            }
+            namespace.apply(&mut pattern);
+            counters.pattern_decoded();
+            sender.send(Arc::new(pattern)).await?;
         }
     }
 }
 
+/// Replays patterns decoded before the benchmark started, starting over at
+/// the end.
+pub(crate) async fn feed_from_memory(
+    patterns: Vec<Arc<ExecPattern>>,
+    sender: tokio::sync::mpsc::Sender<Arc<ExecPattern>>,
+    counters: Arc<ClientCounters>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    for pattern in patterns.iter().cycle() {
+        counters.pattern_decoded();
+        sender.send(pattern.clone()).await?;
+    }
+    Ok(())
+}
+
 pub(crate) async fn feed_test(
-    repetitions: impl Into<usize>,
-    pattern: ExecPattern,
+    patterns: Vec<ExecPattern>,
     kill_switch: Arc<AtomicBool>,
-    sender: tokio::sync::mpsc::Sender<ExecPattern>,
+    sender: tokio::sync::mpsc::Sender<Arc<ExecPattern>>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     println!("Initiating test feeder");
 
-    let repetitions: usize = repetitions.into();
-    for _ in 0..repetitions {
-        let local_pattern = pattern.clone();
+    for local_pattern in patterns {
         println!("sending local pattern");
-        sender.send(local_pattern).await.unwrap();
+        sender.send(Arc::new(local_pattern)).await.unwrap();
     }
 
     kill_switch.store(true, std::sync::atomic::Ordering::Relaxed);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_feed_from_file_starts_over_at_the_end_of_the_range() {
+    use crate::datafile::{DataFileMetadata, PatternWriter};
+    use crate::pattern::basic::{BasicCommand, BasicPattern};
+
+    let path = std::env::temp_dir().join(format!("feed-test-{}.bin", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    let mut writer = PatternWriter::new(file, 0, &DataFileMetadata::default()).unwrap();
+    for key in ["a", "b", "c", "d"] {
+        let get = BasicCommand::Get {
+            key: key.to_string(),
+        };
+        writer
+            .write_pattern(&BasicPattern(
+                vec![get],
+                vec!["not found".to_string()],
+                None,
+            ))
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let data_file = DataFile::open(&path).await.unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+    let feeder = tokio::spawn(feed_from_file(
+        data_file,
+        1..3,
+        sender,
+        KeyNamespace::new(None, false),
+        Arc::default(),
+    ));
+    let mut keys = Vec::new();
+    for _ in 0..5 {
+        keys.push(receiver.recv().await.unwrap().0[0].key().to_string());
+    }
+    feeder.abort();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(keys, ["b", "c", "b", "c", "b"]);
+}
@@ -1,49 +1,160 @@
-use std::collections::BinaryHeap;
-use std::{
-    net::SocketAddr,
-    sync::{atomic::AtomicBool, Arc},
-};
+use std::sync::{atomic::AtomicBool, Arc};
 
 use comfy_table::Table;
 use tokio::sync::Semaphore;
 
+use crate::connection::{Connector, Target};
+use crate::options::TlsArgs;
 use crate::pattern::basic::BasicState;
+use crate::results::{print_summaries, summaries_by_class};
+use crate::routing::{Router, Routing};
+use crate::think_time::ThinkTimes;
+use crate::value_size::ValueSize;
 use crate::{
-    pattern::{ExecPattern, ParsePattern},
-    supplier::{feed_chans, feed_test, PatternResponse, TimeResult},
-    worker::worker,
+    pattern::{ExecPattern, PatternMix},
+    supplier::{feed_shared, feed_test, PatternResponse, TimeResult},
+    worker::{worker, ConnectionMode},
 };
 
+/// Settings of a test, which executes freshly generated patterns one after
+/// another against a single host.
+#[derive(Debug, Clone)]
+pub struct TestConfig {
+    repetitions: usize,
+    target: Target,
+    pattern: PatternMix,
+    key_size: usize,
+    value_size: ValueSize,
+    tls: TlsArgs,
+}
+
+impl TestConfig {
+    /// Executes a single `SET-GET-GET-DEL` pattern against `target`.
+    pub fn new(target: Target) -> Self {
+        Self {
+            repetitions: 1,
+            target,
+            pattern: "SET-GET-GET-DEL".parse().unwrap(),
+            key_size: 10,
+            value_size: ValueSize::default(),
+            tls: TlsArgs::default(),
+        }
+    }
+
+    /// Number of patterns executed.
+    pub fn repetitions(mut self, repetitions: usize) -> Self {
+        self.repetitions = repetitions;
+        self
+    }
+
+    pub fn pattern(mut self, pattern: PatternMix) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    pub fn key_size(mut self, key_size: usize) -> Self {
+        self.key_size = key_size;
+        self
+    }
+
+    pub fn value_size(mut self, value_size: ValueSize) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsArgs) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub async fn run(self) -> Result<TestReport, Box<dyn std::error::Error + Send + Sync>> {
+        let connector = Connector::new(vec![self.target], &self.tls)?;
+        perform_test(
+            self.repetitions,
+            connector,
+            self.pattern,
+            self.key_size,
+            self.value_size,
+        )
+        .await
+    }
+}
+
+/// Responses of a test, in the order the patterns were executed.
+#[derive(Debug)]
+pub struct TestReport {
+    pub responses: Vec<PatternResponse>,
+}
+
+impl TestReport {
+    /// Number of commands that failed or whose response didn't match the
+    /// prediction.
+    pub fn errors(&self) -> usize {
+        self.responses
+            .iter()
+            .flat_map(|response| response.timing.durations.iter())
+            .filter(|duration| duration.is_err())
+            .count()
+    }
+
+    /// Prints a table per pattern, followed by the summaries per pattern
+    /// class.
+    pub fn print(&self) {
+        for response in self.responses.iter().rev() {
+            print_response(response);
+        }
+        print_summaries("pattern", &mut summaries_by_class(self.responses.iter()));
+    }
+}
+
 pub(crate) async fn perform_test(
     repetitions: usize,
-    host: SocketAddr,
-    pattern: ParsePattern,
+    connector: Connector,
+    pattern: PatternMix,
     key_size: usize,
-    value_size: usize,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    value_size: ValueSize,
+) -> Result<TestReport, Box<dyn std::error::Error + Send + Sync>> {
     let (_kill_switch_sender, kill_switch_receiver) = tokio::sync::watch::channel(());
 
     let mut state = BasicState::new();
 
-    let exec_pattern = ExecPattern::new(&pattern, key_size, value_size, &mut state);
+    let mut rng = rand::thread_rng();
+    let value_sizes = value_size
+        .sampler()
+        .map_err(|e| format!("invalid value size {} => {}", value_size, e))?;
+    let exec_patterns: Vec<ExecPattern> = (0..repetitions)
+        .map(|_| {
+            let pattern = pattern.choose(&mut rng);
+            ExecPattern::new(
+                pattern,
+                key_size,
+                &value_sizes,
+                None,
+                None,
+                false,
+                &mut state,
+            )
+        })
+        .collect::<Result<_, String>>()?;
     let kill_switch = Arc::new(AtomicBool::new(false));
     let (decoder_sender, decoder_receiver) = tokio::sync::mpsc::channel(1000);
-    let (worker_sender, worker_receiver) = tokio::sync::mpsc::channel(1_00000000);
-
-    let worker_chans = vec![worker_sender];
+    let (worker_sender, worker_receiver) = flume::unbounded();
     let activator = Arc::new(Semaphore::new(0));
     let worker_activator = activator.clone();
 
-    let worker_host = Arc::new(host);
+    let router = Router::new(Routing::RoundRobin, &connector.targets);
+    let worker_connector = Arc::new(connector);
     let worker_kill_switch = kill_switch_receiver.clone();
 
     let worker_handle = tokio::spawn(async move {
-        let inner_host = worker_host;
         worker(
             worker_receiver,
-            *inner_host,
+            worker_connector,
             worker_kill_switch,
             worker_activator,
+            ConnectionMode::PerPattern,
+            ThinkTimes::default(),
+            Arc::default(),
         )
         .await
     });
@@ -53,46 +164,61 @@ pub(crate) async fn perform_test(
     let feeder_kill_switch = kill_switch_receiver.clone();
 
     let feeder_handle = tokio::spawn(async move {
-        feed_chans::<true>(decoder_receiver, worker_chans, feeder_kill_switch).await
+        feed_shared(decoder_receiver, worker_sender, feeder_kill_switch, router).await
     });
 
-    let decoder_handle = tokio::spawn(async move {
-        feed_test(repetitions, exec_pattern, kill_switch, decoder_sender).await
-    });
+    let decoder_handle =
+        tokio::spawn(async move { feed_test(exec_patterns, kill_switch, decoder_sender).await });
 
     feeder_handle.await??;
     decoder_handle.await??;
     let m = worker_handle.await??;
-    test_resp_printer(m);
 
-    Ok(())
+    Ok(TestReport {
+        responses: m.into_sorted_vec(),
+    })
 }
 
-fn test_resp_printer(mut results: BinaryHeap<PatternResponse>) {
-    while !results.is_empty() {
-        let response = results.pop().unwrap();
-        let pattern = response.pattern;
-        let TimeResult {
-            durations,
-            total_duration,
-            start_time,
-        } = response.timing;
-
-        let mut table = Table::new();
-
-        let mut header: Vec<String> = pattern.0.iter().map(ToString::to_string).collect();
-        header.push("total duration".to_string());
-        header.push("start time".to_string());
-        let mut row: Vec<String> = durations
-            .iter()
-            .map(|e| match e {
-                Ok(dur) => format!("Ok: {:?}", dur),
-                Err(e) => format!("Err: {:?}", e),
-            })
-            .collect();
-        row.push(format!("{:?}", total_duration));
-        row.push(format!("{:?}", start_time));
-        table.set_header(header).add_row(row);
-        println!("{table}");
+fn print_response(response: &PatternResponse) {
+    let TimeResult {
+        durations,
+        first_byte_durations,
+        total_duration,
+        start_time,
+        handshake_duration,
+        connect_duration,
+        close_duration,
+        ..
+    } = &response.timing;
+
+    let mut table = Table::new();
+
+    let mut header: Vec<String> = response.pattern.0.iter().map(ToString::to_string).collect();
+    header.push("total duration".to_string());
+    header.push("start time".to_string());
+    let mut row: Vec<String> = durations
+        .iter()
+        .zip(first_byte_durations.iter())
+        .map(|e| match e {
+            (Ok(dur), Some(first_byte)) => {
+                format!("Ok: {:?} (first byte {:?})", dur, first_byte)
+            }
+            (Ok(dur), None) => format!("Ok: {:?}", dur),
+            (Err(e), _) => format!("Err: {:?}", e),
+        })
+        .collect();
+    row.push(format!("{:?}", total_duration));
+    row.push(format!("{:?}", start_time));
+    for (name, duration) in [
+        ("connect", connect_duration),
+        ("tls handshake", handshake_duration),
+        ("close", close_duration),
+    ] {
+        if let Some(duration) = duration {
+            header.push(name.to_string());
+            row.push(format!("{:?}", duration));
+        }
     }
+    table.set_header(header).add_row(row);
+    println!("{table}");
 }
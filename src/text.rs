@@ -0,0 +1,403 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use clap::ArgEnum;
+use tokio::io::AsyncWriteExt;
+
+use crate::datafile::{DataFile, DataFileMetadata, PatternWriter};
+use crate::pattern::basic::{BasicCommand, BasicPattern, BasicState};
+use crate::pattern::ExecPattern;
+
+const FORMAT_HELP: &str = "\
+# one pattern per line, its commands separated by ` | `, each followed by
+# ` => ` and the predicted response, e.g. `SET k v => not found | GET k => v`
+# `[n] ` in front of a pattern puts it into key partition n
+# `\\`, `|`, `>` and line breaks in keys, values and responses are escaped
+# with a backslash";
+
+/// Where the predicted responses of imported patterns come from.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predictions {
+    /// take the responses written after ` => `, every command needs one
+    Verbatim,
+    /// replay the patterns one after another against a model of the server
+    /// holding the preloaded keys, responses in the file are ignored
+    Recompute,
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' | '|' | '>' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c @ ('\\' | '|' | '>')) => unescaped.push(c),
+            Some(c) => return Err(format!("unknown escape sequence \\{}", c)),
+            None => return Err("dangling backslash".to_string()),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// Splits `s` at every `separator` whose special character, `|` or `>`, isn't
+/// escaped. Unescaped special characters only ever appear in separators.
+fn split_unescaped<'a>(s: &'a str, separator: &str, special: u8) -> Result<Vec<&'a str>, String> {
+    let offset = separator.bytes().position(|b| b == special).unwrap();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (idx, b) in s.bytes().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if b == b'\\' {
+            escaped = true;
+        } else if b == special {
+            let separator_start = idx
+                .checked_sub(offset)
+                .filter(|&i| s.get(i..).is_some_and(|rest| rest.starts_with(separator)))
+                .ok_or_else(|| format!("expected {:?} around {:?}", separator, b as char))?;
+            parts.push(&s[start..separator_start]);
+            start = separator_start + separator.len();
+        }
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+fn format_pattern(pattern: &ExecPattern) -> String {
+    let commands: Vec<String> = pattern
+        .0
+        .iter()
+        .zip(pattern.1.iter())
+        .map(|(command, prediction)| {
+            let prediction = prediction.strip_suffix('\n').unwrap_or(prediction);
+            format!("{} => {}", escape(&command.to_string()), escape(prediction))
+        })
+        .collect();
+    match pattern.2 {
+        Some(partition) => format!("[{}] {}", partition, commands.join(" | ")),
+        None => commands.join(" | "),
+    }
+}
+
+/// A pattern as written in the text file.
+struct PatternLine {
+    commands: Vec<BasicCommand>,
+    /// `None` for commands written without a prediction
+    predictions: Vec<Option<String>>,
+    partition: Option<u32>,
+}
+
+fn parse_pattern(line: &str) -> Result<PatternLine, String> {
+    let (partition, line) = match line.strip_prefix('[') {
+        Some(rest) => {
+            let (partition, rest) = rest
+                .split_once("] ")
+                .ok_or("expected `] ` after the partition")?;
+            let partition = partition
+                .parse::<u32>()
+                .map_err(|e| format!("invalid partition {:?} => {}", partition, e))?;
+            (Some(partition), rest)
+        }
+        None => (None, line),
+    };
+
+    let mut commands = Vec::new();
+    let mut predictions = Vec::new();
+    for part in split_unescaped(line, " | ", b'|')? {
+        let (command, prediction) = match split_unescaped(part, " => ", b'>')?.as_slice() {
+            [command] => (*command, None),
+            [command, prediction] => (*command, Some(format!("{}\n", unescape(prediction)?))),
+            _ => return Err(format!("more than one ` => ` in {:?}", part)),
+        };
+        let command = unescape(command)?;
+        commands.push(
+            command
+                .parse::<BasicCommand>()
+                .map_err(|e| format!("{} in {:?}", e, command))?,
+        );
+        predictions.push(prediction);
+    }
+    Ok(PatternLine {
+        commands,
+        predictions,
+        partition,
+    })
+}
+
+fn write_metadata(out: &mut impl Write, metadata: &DataFileMetadata) -> std::io::Result<()> {
+    if metadata.partitions > 0 {
+        writeln!(out, "@partitions {}", metadata.partitions)?;
+    }
+    if let Some(think_time) = metadata.think_times.command {
+        writeln!(out, "@think_time {}", think_time)?;
+    }
+    if let Some(think_time) = metadata.think_times.pattern {
+        writeln!(out, "@pattern_think_time {}", think_time)?;
+    }
+    if let Some(value_size) = &metadata.value_size {
+        writeln!(out, "@value_size {}", value_size)?;
+    }
+    Ok(())
+}
+
+/// Applies a `@name value` directive to the metadata or the preloaded pairs.
+fn parse_directive(
+    directive: &str,
+    metadata: &mut DataFileMetadata,
+    preload: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    let (name, value) = directive
+        .split_once(' ')
+        .ok_or_else(|| format!("missing value of @{}", directive))?;
+    match name {
+        "partitions" => {
+            metadata.partitions = value
+                .parse()
+                .map_err(|e| format!("invalid partitions {:?} => {}", value, e))?;
+        }
+        "think_time" => metadata.think_times.command = Some(value.parse()?),
+        "pattern_think_time" => metadata.think_times.pattern = Some(value.parse()?),
+        "value_size" => metadata.value_size = Some(value.parse()?),
+        "preload" => {
+            let (key, value) = value
+                .split_once(' ')
+                .ok_or("expected a key and a value to preload")?;
+            preload.push((unescape(key)?, unescape(value)?));
+        }
+        _ => return Err(format!("unknown directive @{}", name)),
+    }
+    Ok(())
+}
+
+/// Writes the patterns of a data file as text, one per line. Returns the
+/// number of patterns written.
+pub async fn export_text(
+    data_in: impl AsRef<Path>,
+    text_out: impl AsRef<Path>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let data_file = DataFile::open(data_in).await?;
+    let mut out = tokio::io::BufWriter::new(tokio::fs::File::create(text_out).await?);
+    // each section is formatted in memory and written out as a whole
+    let mut text = Vec::new();
+    writeln!(text, "{}", FORMAT_HELP)?;
+    write_metadata(&mut text, &data_file.metadata)?;
+    for (key, value) in data_file.read_preload().await? {
+        writeln!(text, "@preload {} {}", escape(&key), escape(&value))?;
+    }
+    out.write_all(&text).await?;
+    for chunk in 0..data_file.chunks().len() {
+        text.clear();
+        for pattern in data_file.read_chunk(chunk).await? {
+            writeln!(text, "{}", format_pattern(&pattern))?;
+        }
+        out.write_all(&text).await?;
+    }
+    out.flush().await?;
+    Ok(data_file.patterns())
+}
+
+/// Starts a data file holding the metadata and the key value pairs to
+/// preload.
+fn create_data_file(
+    data_out: &Path,
+    compression_level: i32,
+    mut metadata: DataFileMetadata,
+    preload: &[(String, String)],
+) -> std::io::Result<PatternWriter<File>> {
+    metadata.preload = preload.len() as u64;
+    let mut writer = PatternWriter::new(File::create(data_out)?, compression_level, &metadata)?;
+    for (key, value) in preload {
+        writer.write_preload(key, value)?;
+    }
+    Ok(writer)
+}
+
+/// Converts a text file written by [`export_text`] or by hand back into a
+/// data file. Returns the number of patterns written.
+///
+/// Lines starting with `#` are comments. The metadata and the key value
+/// pairs to preload are given before the first pattern by `@partitions`,
+/// `@think_time`, `@pattern_think_time`, `@value_size` and `@preload key value`
+/// lines. Patterns can only be put into a key partition with `[n] ` if
+/// `@partitions` gives more than `n` partitions.
+pub async fn import_text(
+    text_in: impl AsRef<Path>,
+    data_out: impl AsRef<Path>,
+    compression_level: i32,
+    predictions: Predictions,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let (text_in, data_out) = (text_in.as_ref().to_owned(), data_out.as_ref().to_owned());
+    tokio::task::spawn_blocking(move || import(&text_in, &data_out, compression_level, predictions))
+        .await?
+}
+
+/// Reads the text file line by line and writes the data file, blocking.
+fn import(
+    text_in: &Path,
+    data_out: &Path,
+    compression_level: i32,
+    predictions: Predictions,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let text = BufReader::new(File::open(text_in)?);
+    let mut metadata = DataFileMetadata::default();
+    let mut preload = Vec::new();
+    let mut writer: Option<PatternWriter<File>> = None;
+    let mut state = BasicState::new();
+    let mut patterns = 0;
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line?;
+        let at_line = |e: String| format!("line {} => {}", idx + 1, e);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(directive) = line.strip_prefix('@') {
+            if writer.is_some() {
+                return Err(
+                    at_line("directives have to come before the first pattern".into()).into(),
+                );
+            }
+            parse_directive(directive, &mut metadata, &mut preload).map_err(at_line)?;
+            continue;
+        }
+
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => {
+                state.extend(preload.iter().cloned());
+                writer.insert(create_data_file(
+                    data_out,
+                    compression_level,
+                    metadata.clone(),
+                    &preload,
+                )?)
+            }
+        };
+
+        let PatternLine {
+            commands,
+            predictions: written,
+            partition,
+        } = parse_pattern(&line).map_err(at_line)?;
+        if let Some(partition) = partition {
+            if partition >= metadata.partitions {
+                return Err(at_line(format!(
+                    "partition {} is out of the {} partitions given by @partitions",
+                    partition, metadata.partitions
+                ))
+                .into());
+            }
+        }
+        let predicted = match predictions {
+            Predictions::Verbatim => written
+                .into_iter()
+                .collect::<Option<Vec<String>>>()
+                .ok_or_else(|| {
+                    at_line(
+                        "a command has no prediction, import with `--predictions recompute` to compute them"
+                            .into(),
+                    )
+                })?,
+            Predictions::Recompute => commands.iter().map(|c| c.predict(&mut state)).collect(),
+        };
+        writer.write_pattern(&BasicPattern(commands, predicted, partition))?;
+        patterns += 1;
+    }
+
+    // a file without patterns still carries its metadata and preload
+    let writer = match writer {
+        Some(writer) => writer,
+        None => create_data_file(data_out, compression_level, metadata, &preload)?,
+    };
+    writer.finish()?;
+    Ok(patterns)
+}
+
+#[tokio::test]
+async fn test_text_round_trip() {
+    let dir = std::env::temp_dir();
+    let id = std::process::id();
+    let (text_in, data, text_out) = (
+        dir.join(format!("import-{}.txt", id)),
+        dir.join(format!("import-{}.bin", id)),
+        dir.join(format!("export-{}.txt", id)),
+    );
+    std::fs::write(
+        &text_in,
+        "# hand-written\n\
+         @partitions 2\n\
+         @think_time 1ms\n\
+         @preload a 1\n\
+         [1] SET b x\\|y\\>z => stale | GET b\n\
+         GET a | DEL a | GET a\n",
+    )
+    .unwrap();
+
+    assert!(import_text(&text_in, &data, 0, Predictions::Verbatim)
+        .await
+        .is_err());
+    assert_eq!(
+        import_text(&text_in, &data, 0, Predictions::Recompute)
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(export_text(&data, &text_out).await.unwrap(), 2);
+    let exported = std::fs::read_to_string(&text_out).unwrap();
+    let lines: Vec<&str> = exported.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(
+        lines,
+        [
+            "@partitions 2",
+            "@think_time fixed:1ms",
+            "@preload a 1",
+            "[1] SET b x\\|y\\>z => not found | GET b => x\\|y\\>z",
+            "GET a => 1 | DEL a => 1 | GET a => not found",
+        ]
+    );
+
+    // the export is imported verbatim into the same patterns
+    import_text(&text_out, &data, 0, Predictions::Verbatim)
+        .await
+        .unwrap();
+    export_text(&data, &text_in).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&text_in).unwrap(), exported);
+
+    // a separator right after a multi-byte character, partitions missing or
+    // out of range
+    for invalid in [
+        "GET é| GET b\n",
+        "[0] GET a\n",
+        "@partitions 2\n[2] GET a\n",
+    ] {
+        std::fs::write(&text_in, invalid).unwrap();
+        assert!(import_text(&text_in, &data, 0, Predictions::Recompute)
+            .await
+            .is_err());
+    }
+
+    for path in [text_in, data, text_out] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Distribution of the pauses a client makes, emulating an interactive
+/// session rather than firing commands back to back.
+///
+/// Parsed from `10ms` or `fixed:10ms`, `uniform:1ms..5ms` and `exp:2ms`,
+/// where the latter is an exponential distribution with the given mean.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ThinkTime {
+    Fixed(Duration),
+    Uniform { min: Duration, max: Duration },
+    Exponential { mean: Duration },
+}
+
+impl ThinkTime {
+    pub(crate) fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        match *self {
+            ThinkTime::Fixed(pause) => pause,
+            ThinkTime::Uniform { min, max } if min < max => rng.gen_range(min..=max),
+            ThinkTime::Uniform { min, .. } => min,
+            ThinkTime::Exponential { mean } => {
+                // inverse transform sampling, `1 - u` is in (0, 1]
+                let u: f64 = rng.gen();
+                mean.mul_f64(-(1.0 - u).ln())
+            }
+        }
+    }
+}
+
+impl FromStr for ThinkTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |d: &str| {
+            parse_duration::parse(d.trim())
+                .map_err(|e| format!("invalid think time {:?} => {}", s, e))
+        };
+        match s.split_once(':') {
+            None => Ok(Self::Fixed(parse(s)?)),
+            Some(("fixed", pause)) => Ok(Self::Fixed(parse(pause)?)),
+            Some(("uniform", range)) => {
+                let (min, max) = range
+                    .split_once("..")
+                    .ok_or_else(|| format!("expected a range like 1ms..5ms, found {:?}", range))?;
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min > max {
+                    return Err(format!("empty think time range {:?}", range));
+                }
+                Ok(Self::Uniform { min, max })
+            }
+            Some(("exp", mean)) => Ok(Self::Exponential { mean: parse(mean)? }),
+            Some((kind, _)) => Err(format!(
+                "unknown think time distribution {:?}, expected fixed, uniform or exp",
+                kind
+            )),
+        }
+    }
+}
+
+impl Display for ThinkTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThinkTime::Fixed(pause) => write!(f, "fixed:{:?}", pause),
+            ThinkTime::Uniform { min, max } => write!(f, "uniform:{:?}..{:?}", min, max),
+            ThinkTime::Exponential { mean } => write!(f, "exp:{:?}", mean),
+        }
+    }
+}
+
+/// Think times of a workload, between the commands of a pattern and between
+/// the patterns a worker executes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ThinkTimes {
+    pub command: Option<ThinkTime>,
+    pub pattern: Option<ThinkTime>,
+}
+
+impl ThinkTimes {
+    /// Think times given at run time take precedence over the ones stored in
+    /// the data file.
+    pub(crate) fn or(self, other: Self) -> Self {
+        Self {
+            command: self.command.or(other.command),
+            pattern: self.pattern.or(other.pattern),
+        }
+    }
+}
+
+/// Sleeps for a pause drawn from the distribution and returns how long it
+/// actually slept, which includes the overshoot of the timer.
+pub(crate) async fn think(think_time: Option<&ThinkTime>) -> Duration {
+    match think_time {
+        None => Duration::ZERO,
+        Some(think_time) => {
+            let pause = think_time.sample(&mut rand::thread_rng());
+            let start = tokio::time::Instant::now();
+            tokio::time::sleep(pause).await;
+            start.elapsed()
+        }
+    }
+}
+
+#[test]
+fn test_think_time() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let fixed: ThinkTime = "10ms".parse().unwrap();
+    assert_eq!(fixed, ThinkTime::Fixed(Duration::from_millis(10)));
+    assert_eq!("fixed:10ms".parse::<ThinkTime>().unwrap(), fixed);
+    assert!("uniform:5ms..1ms".parse::<ThinkTime>().is_err());
+    assert!("normal:5ms".parse::<ThinkTime>().is_err());
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let uniform: ThinkTime = "uniform:1ms..3ms".parse().unwrap();
+    for _ in 0..100 {
+        let pause = uniform.sample(&mut rng);
+        assert!(pause >= Duration::from_millis(1) && pause <= Duration::from_millis(3));
+    }
+
+    let exponential: ThinkTime = "exp:2ms".parse().unwrap();
+    let total: Duration = (0..10000).map(|_| exponential.sample(&mut rng)).sum();
+    let mean = total / 10000;
+    assert!(
+        mean > Duration::from_micros(1800) && mean < Duration::from_micros(2200),
+        "mean {:?}",
+        mean
+    );
+}
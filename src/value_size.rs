@@ -0,0 +1,272 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use rand_distr::{LogNormal, Normal};
+use serde::{Deserialize, Serialize};
+
+/// Largest value size a distribution may produce, larger samples of
+/// distributions with long tails are cut down to it.
+pub(crate) const MAX_VALUE_SIZE: usize = 1 << 20;
+
+/// Distribution the sizes of generated values are drawn from.
+///
+/// Parsed from
+/// * `10`, every value has 10 characters
+/// * `uniform:10..100`, inclusive range
+/// * `normal:100,20`, mean and standard deviation
+/// * `lognormal:4.6,0.5`, mean and standard deviation of the logarithm
+/// * `empirical:16=50,128=30,4096=20`, sizes with their weights
+/// * `empirical:@sizes.txt`, a file with one `size weight` pair per line
+///
+/// Sizes have to be between 1 and 1 MiB, so every value can be sent. The
+/// normal and log-normal distributions only need their median in that range,
+/// samples beyond it are clamped.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ValueSize {
+    Fixed(usize),
+    Uniform { min: usize, max: usize },
+    Normal { mean: f64, std_dev: f64 },
+    LogNormal { mu: f64, sigma: f64 },
+    Empirical(Vec<(usize, u32)>),
+}
+
+impl Default for ValueSize {
+    fn default() -> Self {
+        Self::Fixed(10)
+    }
+}
+
+impl ValueSize {
+    /// Prepares the distribution for sampling, failing if it can't produce
+    /// sizes between 1 and `MAX_VALUE_SIZE`.
+    pub(crate) fn sampler(&self) -> Result<ValueSizeSampler, String> {
+        let check_size = |size: usize| {
+            if (1..=MAX_VALUE_SIZE).contains(&size) {
+                Ok(size)
+            } else {
+                Err(format!(
+                    "size {} is not between 1 and {}",
+                    size, MAX_VALUE_SIZE
+                ))
+            }
+        };
+        let check_median = |median: f64, what: &str| {
+            if median >= 1.0 && median <= MAX_VALUE_SIZE as f64 {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} has to be between 1 and {}",
+                    what, MAX_VALUE_SIZE
+                ))
+            }
+        };
+        let inner = match self {
+            ValueSize::Fixed(size) => SamplerInner::Fixed(check_size(*size)?),
+            ValueSize::Uniform { min, max } => {
+                if min > max {
+                    return Err("empty range".to_string());
+                }
+                SamplerInner::Uniform(check_size(*min)?, check_size(*max)?)
+            }
+            ValueSize::Normal { mean, std_dev } => {
+                check_median(*mean, "the mean")?;
+                SamplerInner::Normal(Normal::new(*mean, *std_dev).map_err(|e| e.to_string())?)
+            }
+            ValueSize::LogNormal { mu, sigma } => {
+                check_median(mu.exp(), "the median exp(mu)")?;
+                SamplerInner::LogNormal(LogNormal::new(*mu, *sigma).map_err(|e| e.to_string())?)
+            }
+            ValueSize::Empirical(buckets) => SamplerInner::Empirical(
+                buckets
+                    .iter()
+                    .map(|(size, _)| check_size(*size))
+                    .collect::<Result<_, _>>()?,
+                WeightedIndex::new(buckets.iter().map(|(_, weight)| *weight))
+                    .map_err(|e| e.to_string())?,
+            ),
+        };
+        Ok(ValueSizeSampler(inner))
+    }
+}
+
+pub(crate) struct ValueSizeSampler(SamplerInner);
+
+enum SamplerInner {
+    Fixed(usize),
+    Uniform(usize, usize),
+    Normal(Normal<f64>),
+    LogNormal(LogNormal<f64>),
+    Empirical(Vec<usize>, WeightedIndex<u32>),
+}
+
+impl ValueSizeSampler {
+    pub(crate) fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let size = match &self.0 {
+            SamplerInner::Fixed(size) => *size,
+            SamplerInner::Uniform(min, max) => rng.gen_range(*min..=*max),
+            SamplerInner::Normal(normal) => normal.sample(rng).round() as usize,
+            SamplerInner::LogNormal(log_normal) => log_normal.sample(rng).round() as usize,
+            SamplerInner::Empirical(sizes, index) => sizes[index.sample(rng)],
+        };
+        size.clamp(1, MAX_VALUE_SIZE)
+    }
+}
+
+impl FromStr for ValueSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid value size {:?} => {}", s, reason);
+        let number = |n: &str| n.trim().parse::<f64>().map_err(|e| invalid(&e.to_string()));
+        let size = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|e| invalid(&e.to_string()))
+        };
+        let pair = |params: &str| {
+            let (a, b) = params
+                .split_once(',')
+                .ok_or_else(|| invalid("expected two comma separated parameters"))?;
+            let (a, b) = (number(a)?, number(b)?);
+            if !(b.is_finite() && b >= 0.0) {
+                return Err(invalid("the spread must not be negative"));
+            }
+            Ok((a, b))
+        };
+
+        let ret = match s.split_once(':') {
+            None => Self::Fixed(size(s)?),
+            Some(("uniform", range)) => {
+                let (min, max) = range
+                    .split_once("..")
+                    .ok_or_else(|| invalid("expected a range like 10..100"))?;
+                let (min, max) = (size(min)?, size(max)?);
+                Self::Uniform { min, max }
+            }
+            Some(("normal", params)) => {
+                let (mean, std_dev) = pair(params)?;
+                Self::Normal { mean, std_dev }
+            }
+            Some(("lognormal", params)) => {
+                let (mu, sigma) = pair(params)?;
+                Self::LogNormal { mu, sigma }
+            }
+            Some(("empirical", histogram)) => {
+                let buckets = match histogram.strip_prefix('@') {
+                    Some(path) => read_histogram(path).map_err(|e| invalid(&e))?,
+                    None => histogram
+                        .split(',')
+                        .map(|bucket| bucket.split_once('='))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| invalid("expected buckets like 16=50,128=50"))?
+                        .into_iter()
+                        .map(|(size, weight)| bucket(size, weight).map_err(|e| invalid(&e)))
+                        .collect::<Result<Vec<_>, _>>()?,
+                };
+                Self::Empirical(buckets)
+            }
+            Some((kind, _)) => {
+                return Err(invalid(&format!(
+                    "unknown distribution {:?}, expected uniform, normal, lognormal or empirical",
+                    kind
+                )))
+            }
+        };
+        ret.sampler().map_err(|e| invalid(&e))?;
+        Ok(ret)
+    }
+}
+
+fn bucket(size: &str, weight: &str) -> Result<(usize, u32), String> {
+    let size = size
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", size))?;
+    let weight = weight
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid weight {:?}", weight))?;
+    Ok((size, weight))
+}
+
+/// Reads a histogram with one `size weight` pair per line, lines starting
+/// with `#` are ignored.
+fn read_histogram(path: &str) -> Result<Vec<(usize, u32)>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{:?} => {}", path, e))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(size), Some(weight), None) => bucket(size, weight),
+                _ => Err(format!("expected `size weight`, found {:?}", line)),
+            }
+        })
+        .collect()
+}
+
+impl Display for ValueSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueSize::Fixed(size) => write!(f, "{}", size),
+            ValueSize::Uniform { min, max } => write!(f, "uniform:{}..{}", min, max),
+            ValueSize::Normal { mean, std_dev } => write!(f, "normal:{},{}", mean, std_dev),
+            ValueSize::LogNormal { mu, sigma } => write!(f, "lognormal:{},{}", mu, sigma),
+            ValueSize::Empirical(buckets) => {
+                f.write_str("empirical:")?;
+                for (idx, (size, weight)) in buckets.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}={}", size, weight)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_value_size_distributions() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut mean_of = |spec: &str| {
+        let value_size: ValueSize = spec.parse().unwrap();
+        assert_eq!(
+            value_size.to_string().parse::<ValueSize>().unwrap(),
+            value_size
+        );
+        let sampler = value_size.sampler().unwrap();
+        (0..10000).map(|_| sampler.sample(&mut rng)).sum::<usize>() as f64 / 10000.0
+    };
+
+    assert_eq!(mean_of("10"), 10.0);
+    assert!((mean_of("uniform:10..30") - 20.0).abs() < 1.0);
+    assert!((mean_of("normal:100,10") - 100.0).abs() < 1.0);
+    // the mean of a log-normal distribution is exp(mu + sigma^2 / 2)
+    assert!((mean_of("lognormal:4,0.5") - 61.9).abs() < 2.0);
+    assert!((mean_of("empirical:10=1,30=1") - 20.0).abs() < 1.0);
+
+    assert!("uniform:30..10".parse::<ValueSize>().is_err());
+    assert!("0".parse::<ValueSize>().is_err());
+    assert!("uniform:0..10".parse::<ValueSize>().is_err());
+    assert!("empirical:16=1,4294967296=1".parse::<ValueSize>().is_err());
+    assert!("normal:1e12,1".parse::<ValueSize>().is_err());
+    assert!("lognormal:40,1".parse::<ValueSize>().is_err());
+
+    // a long tail is cut off instead of asking for gigabytes
+    let long_tail = "lognormal:10,5"
+        .parse::<ValueSize>()
+        .unwrap()
+        .sampler()
+        .unwrap();
+    assert!((0..10000).all(|_| long_tail.sample(&mut rng) <= MAX_VALUE_SIZE));
+    assert!("normal:10,-1".parse::<ValueSize>().is_err());
+    assert!("empirical:10=0".parse::<ValueSize>().is_err());
+    assert!("pareto:1,2".parse::<ValueSize>().is_err());
+}
@@ -4,86 +4,387 @@ use std::sync::Arc;
 use std::time::Duration;
 
 // use flume::{Receiver, TryRecvError};
-use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::Receiver;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::{io::BufStream, net::TcpStream, sync::Semaphore, time::Instant};
 
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::connection::{Connector, Target};
+use crate::monitor::ClientCounters;
+use crate::pattern::basic::{BasicCommand, BasicPattern};
+use crate::pattern::ExecPattern;
+use crate::routing::{Router, Routing};
 use crate::supplier::{PatternBundle, PatternResponse, TimeResult};
+use crate::think_time::{think, ThinkTimes};
+
+/// Whether a new connection is opened for every pattern or a worker keeps
+/// one connection per host open and executes all its patterns on it.
+#[derive(ArgEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectionMode {
+    PerPattern,
+    Persistent,
+}
+
+/// Any stream a pattern can be executed on.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+type Connection = BufStream<Box<dyn Stream>>;
 
 pub(crate) async fn worker(
-    mut supplier: Receiver<PatternBundle>,
-    address: std::net::SocketAddr,
+    supplier: flume::Receiver<PatternBundle>,
+    connector: Arc<Connector>,
     mut kill_switch: tokio::sync::watch::Receiver<()>,
     activator: Arc<Semaphore>,
+    mode: ConnectionMode,
+    think_times: ThinkTimes,
+    counters: Arc<ClientCounters>,
 ) -> Result<BinaryHeap<PatternResponse>, Box<dyn std::error::Error + Send + Sync>> {
     activator.acquire().await?.forget();
 
     let mut result_heap = BinaryHeap::new();
+    let mut connections: Vec<Option<Connection>> = connector.targets.iter().map(|_| None).collect();
 
     loop {
-        match kill_switch.has_changed() {
-            Ok(true) => {
-                println!("Got killed exiting");
-                return Ok(result_heap);
+        if !result_heap.is_empty() {
+            tokio::select! {
+                _ = think(think_times.pattern.as_ref()) => {}
+                _ = kill_switch.changed() => break,
             }
-            Ok(_) => {}
-            Err(_) => {
-                return Ok(result_heap);
+        }
+
+        match kill_switch.has_changed() {
+            Ok(false) => {}
+            Ok(true) | Err(_) => break,
+        }
+
+        let bundle = match supplier.try_recv() {
+            Ok(bundle) => bundle,
+            Err(_) => tokio::select! {
+                bundle = supplier.recv_async() => match bundle {
+                    Ok(bundle) => bundle,
+                    Err(_) => break,
+                },
+                _ = kill_switch.changed() => break,
+            },
+        };
+
+        let busy = counters.busy();
+        let response = match mode {
+            ConnectionMode::PerPattern => execute_bundle(&connector, bundle, &think_times).await,
+            ConnectionMode::Persistent => {
+                execute_persistent(&connector, &mut connections, bundle, &think_times).await
             }
+        };
+        drop(busy);
+        result_heap.push(response);
+    }
+
+    for connection in connections.into_iter().flatten() {
+        let mut stream = connection.into_inner();
+        // the server might have closed the connection already
+        let _ = stream.shutdown().await;
+    }
+
+    Ok(result_heap)
+}
+
+/// How long opening a connection took.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ConnectTiming {
+    /// until the TCP or Unix domain socket connection was established
+    pub(crate) connect: Duration,
+    /// duration of the TLS handshake, if TLS is configured
+    pub(crate) handshake: Option<Duration>,
+}
+
+/// Opens a connection to the target and performs the TLS handshake, if TLS
+/// is configured.
+pub(crate) async fn connect(
+    connector: &Connector,
+    target: &Target,
+) -> std::io::Result<(Box<dyn Stream>, ConnectTiming)> {
+    let connect_start = Instant::now();
+    let stream: Box<dyn Stream> = match target {
+        Target::Tcp(address) => {
+            let connection = TcpStream::connect(address).await?;
+            connection.set_linger(Some(Duration::from_millis(1)))?;
+            Box::new(connection)
         }
+        #[cfg(unix)]
+        Target::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+    };
 
-        let mut bundle_opt: Option<PatternBundle> = supplier.try_recv().ok();
+    let connect = connect_start.elapsed();
 
-        if bundle_opt.is_none() {
-            tokio::select! {
-                bundle_result = supplier.recv() => {
-                    if bundle_result.is_none() {
-                        println!("Empty supplier, exiting worker");
-                        return Ok(result_heap);
-                    }
-                    bundle_opt = Some(bundle_result.unwrap());
-                }
-                changed_result = kill_switch.changed() => {
-                    changed_result.unwrap();
-                    return Ok(result_heap);
-                }
+    match &connector.tls {
+        None => Ok((
+            stream,
+            ConnectTiming {
+                connect,
+                handshake: None,
+            },
+        )),
+        Some(tls) => {
+            let handshake_start = Instant::now();
+            let stream = tls
+                .connector
+                .connect(tls.server_name(target), stream)
+                .await?;
+            let timing = ConnectTiming {
+                connect,
+                handshake: Some(handshake_start.elapsed()),
+            };
+            Ok((Box::new(stream), timing))
+        }
+    }
+}
+
+/// Executes the commands over up to `connections` connections per host
+/// without validating the responses. With hash routing every command is only
+/// sent to the host owning its key, otherwise to every host. Returns the
+/// number of commands the server answered with a value.
+pub(crate) async fn execute_bulk(
+    commands: Vec<BasicCommand>,
+    connector: Arc<Connector>,
+    routing: Routing,
+    connections: usize,
+) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let mut router = Router::new(routing, &connector.targets);
+    let mut per_target: Vec<Vec<BasicCommand>> = vec![Vec::new(); connector.targets.len()];
+    for command in commands {
+        if routing == Routing::Hash {
+            let pattern = BasicPattern(vec![command], Vec::new(), None);
+            let target = router.route(&pattern)?;
+            per_target[target].extend(pattern.0);
+        } else {
+            for target_commands in per_target.iter_mut() {
+                target_commands.push(command.clone());
             }
         }
+    }
 
-        let bundle = bundle_opt.unwrap();
-        let response = execute_bundle(&address, bundle).await.unwrap();
-        result_heap.push(response);
+    let mut handles = Vec::new();
+    for (target, commands) in per_target.into_iter().enumerate() {
+        let chunk_size = commands.len().div_ceil(connections.max(1)).max(1);
+        for chunk in commands.chunks(chunk_size) {
+            let chunk = chunk.to_vec();
+            let connector = connector.clone();
+            handles.push(tokio::spawn(async move {
+                execute_commands(&connector, target, chunk).await
+            }));
+        }
     }
+
+    let mut found = 0;
+    for handle in handles {
+        found += handle.await??;
+    }
+    Ok(found)
+}
+
+async fn execute_commands(
+    connector: &Connector,
+    target: usize,
+    commands: Vec<BasicCommand>,
+) -> std::io::Result<usize> {
+    let (stream, _) = connect(connector, &connector.targets[target]).await?;
+    let mut connection = BufStream::new(stream);
+    let mut response = String::new();
+    let mut found = 0;
+    for command in commands {
+        connection
+            .write_all(format!("{}\n", command).as_bytes())
+            .await?;
+        connection.flush().await?;
+        response.clear();
+        if connection.read_line(&mut response).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if response != "not found\n" {
+            found += 1;
+        }
+    }
+    // the server might have closed the connection already
+    let _ = connection.into_inner().shutdown().await;
+    Ok(found)
 }
 
+/// Executes the pattern on a connection opened for it. Failing to connect or
+/// losing the connection is recorded as I/O errors of the affected commands.
 async fn execute_bundle(
-    address: &std::net::SocketAddr,
+    connector: &Connector,
     bundle: PatternBundle,
-) -> Result<PatternResponse, Box<dyn std::error::Error + Send + Sync>> {
+    think_times: &ThinkTimes,
+) -> PatternResponse {
     let pattern = bundle.pattern;
+    let target = &connector.targets[bundle.target];
 
-    let connection = TcpStream::connect(address).await?;
+    let start = Instant::now();
+    let timing = match connect(connector, target).await {
+        Ok((stream, connect_timing)) => {
+            let mut connection = BufStream::new(stream);
+            let mut timing =
+                execute_on(&pattern, &mut connection, Some(connect_timing), think_times).await;
 
-    let mut buf = BufStream::new(connection);
+            let close_start = Instant::now();
+            let mut stream = connection.into_inner();
+            // the commands are done, a connection the server closed already
+            // only goes without a close duration
+            if stream.shutdown().await.is_ok() {
+                timing.close_duration = Some(close_start.elapsed());
+            }
+            timing
+        }
+        Err(e) => TimeResult::failed(pattern.0.len(), e, start),
+    };
 
-    let start_time = Instant::now();
+    PatternResponse {
+        timing,
+        pattern,
+        target: bundle.target,
+    }
+}
 
-    let (durations, total_duration) = pattern.execute(&mut buf).await?;
+/// Executes the pattern on the connection the worker holds to the target,
+/// opening it first if there is none. A connection that failed is dropped
+/// and replaced by the next pattern sent to the same target.
+async fn execute_persistent(
+    connector: &Connector,
+    connections: &mut [Option<Connection>],
+    bundle: PatternBundle,
+    think_times: &ThinkTimes,
+) -> PatternResponse {
+    let pattern = bundle.pattern;
 
-    let mut tcp = buf.into_inner();
-    tcp.flush().await?;
-    tcp.set_linger(Some(Duration::from_millis(1))).unwrap();
-    tcp.shutdown().await?;
+    let mut connect_timing = None;
+    if connections[bundle.target].is_none() {
+        let target = &connector.targets[bundle.target];
+        let start = Instant::now();
+        match connect(connector, target).await {
+            Ok((stream, timing)) => {
+                connect_timing = Some(timing);
+                connections[bundle.target] = Some(BufStream::new(stream));
+            }
+            Err(e) => {
+                return PatternResponse {
+                    timing: TimeResult::failed(pattern.0.len(), e, start),
+                    pattern,
+                    target: bundle.target,
+                };
+            }
+        }
+    }
+
+    let connection = connections[bundle.target].as_mut().unwrap();
+    let timing = execute_on(&pattern, connection, connect_timing, think_times).await;
+    if timing.io_failed() {
+        connections[bundle.target] = None;
+    }
 
-    drop(tcp);
+    PatternResponse {
+        timing,
+        pattern,
+        target: bundle.target,
+    }
+}
 
-    let timing = TimeResult {
-        durations,
-        total_duration,
-        start_time,
+/// Executes the pattern on the connection, `connect_timing` is set if the
+/// connection was opened for this pattern.
+async fn execute_on(
+    pattern: &ExecPattern,
+    connection: &mut Connection,
+    connect_timing: Option<ConnectTiming>,
+    think_times: &ThinkTimes,
+) -> TimeResult {
+    let mut timing = pattern
+        .execute(connection, think_times.command.as_ref())
+        .await;
+    if let Some(connect_timing) = connect_timing {
+        timing.connect_duration = Some(connect_timing.connect);
+        timing.handshake_duration = connect_timing.handshake;
+    }
+    timing
+}
+
+#[tokio::test]
+async fn test_execute_bundle_over_tls() {
+    use crate::options::TlsArgs;
+    use tokio_rustls::rustls;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let ca_path = std::env::temp_dir().join(format!("tls-test-ca-{}.pem", std::process::id()));
+    std::fs::write(&ca_path, cert.serialize_pem().unwrap()).unwrap();
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert.serialize_der().unwrap())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (conn, _) = listener.accept().await.unwrap();
+        let mut conn = BufStream::new(acceptor.accept(conn).await.unwrap());
+        let mut line = String::new();
+        while conn.read_line(&mut line).await.unwrap() > 0 {
+            let response = if line.starts_with("SET") {
+                "not found\n"
+            } else {
+                "value\n"
+            };
+            conn.write_all(response.as_bytes()).await.unwrap();
+            conn.flush().await.unwrap();
+            line.clear();
+        }
+    });
+
+    let args = TlsArgs {
+        tls: true,
+        tls_ca: Some(ca_path.clone()),
+        tls_cert: None,
+        tls_key: None,
+        tls_sni: Some("localhost".to_string()),
+    };
+    let connector = Connector::new(vec![Target::Tcp(address)], &args).unwrap();
+    let pattern = BasicPattern(
+        vec![
+            BasicCommand::Set {
+                key: "key".to_string(),
+                value: "value".to_string(),
+            },
+            BasicCommand::Get {
+                key: "key".to_string(),
+            },
+        ],
+        vec!["not found\n".to_string(), "value\n".to_string()],
+        None,
+    );
+    let bundle = PatternBundle {
+        pattern: Arc::new(pattern),
+        target: 0,
     };
 
-    let response = PatternResponse { timing, pattern };
+    let response = execute_bundle(&connector, bundle, &ThinkTimes::default()).await;
+    std::fs::remove_file(ca_path).unwrap();
 
-    Ok(response)
+    assert!(response.timing.handshake_duration.is_some());
+    assert!(response.timing.connect_duration.is_some());
+    assert!(response.timing.close_duration.is_some());
+    assert!(response.timing.durations.iter().all(Result::is_ok));
+    for (duration, first_byte) in response
+        .timing
+        .durations
+        .iter()
+        .zip(response.timing.first_byte_durations.iter())
+    {
+        assert!(first_byte.unwrap() <= *duration.as_ref().unwrap());
+    }
 }
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use server_language_client::{
+    BasicCommand, BenchmarkConfig, ConnectionMode, GenerateConfig, Routing, Target, TestConfig,
+};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+
+/// Serves the protocol from an in-memory map on a random port.
+async fn spawn_server() -> Target {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let store: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            let store = store.clone();
+            tokio::spawn(async move {
+                let mut conn = BufStream::new(conn);
+                let mut line = String::new();
+                while conn.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    let mut parts = line.trim_end().splitn(3, ' ');
+                    let response = match (parts.next(), parts.next(), parts.next()) {
+                        (Some("GET"), Some(key), None) => store.lock().unwrap().get(key).cloned(),
+                        (Some("SET"), Some(key), Some(value)) => {
+                            store.lock().unwrap().insert(key.into(), value.into())
+                        }
+                        (Some("DEL"), Some(key), None) => store.lock().unwrap().remove(key),
+                        _ => Some("error".to_string()),
+                    };
+                    let response = response.unwrap_or_else(|| "not found".to_string());
+                    conn.write_all(format!("{}\n", response).as_bytes())
+                        .await
+                        .unwrap();
+                    conn.flush().await.unwrap();
+                    line.clear();
+                }
+            });
+        }
+    });
+    Target::Tcp(address)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_run_test_through_the_library() {
+    let target = spawn_server().await;
+
+    let report = TestConfig::new(target)
+        .repetitions(20)
+        .pattern("SET-GET-DEL:1,GET:1".parse().unwrap())
+        .value_size("uniform:1..50".parse().unwrap())
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(report.responses.len(), 20);
+    assert_eq!(report.errors(), 0);
+    for response in report.responses.iter() {
+        assert!(matches!(
+            response.pattern.commands()[0],
+            BasicCommand::Set { .. } | BasicCommand::Get { .. }
+        ));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_preloaded_file_replays_over_several_passes() {
+    let target = spawn_server().await;
+    let data = std::env::temp_dir().join(format!("preload-passes-{}.bin", std::process::id()));
+    GenerateConfig::new(&data)
+        .size(20)
+        .pattern("GET:3,DEL-GET:1,SET-GET-DEL:1".parse().unwrap())
+        .partitions(2)
+        .preload(10)
+        .generate()
+        .await
+        .unwrap();
+
+    let report = BenchmarkConfig::new(vec![target], &data, Duration::from_secs(1))
+        .concurrency(2)
+        .in_memory(true)
+        .run()
+        .await
+        .unwrap();
+    std::fs::remove_file(&data).unwrap();
+
+    assert!(
+        report.responses.len() >= 40,
+        "only {} patterns were replayed",
+        report.responses.len()
+    );
+    let errors: usize = report
+        .hosts
+        .iter()
+        .map(|(_, summary)| summary.errors())
+        .sum();
+    assert_eq!(errors, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_run_benchmark_through_the_library() {
+    let target = spawn_server().await;
+    let dir = std::env::temp_dir();
+    let data = dir.join(format!("run-benchmark-{}.bin", std::process::id()));
+    let results = dir.join(format!("run-benchmark-{}.csv", std::process::id()));
+    let manifest = dir.join(format!(
+        "run-benchmark-{}.manifest.toml",
+        std::process::id()
+    ));
+    GenerateConfig::new(&data)
+        .size(50)
+        .pattern("SET-GET-DEL:1,GET:1".parse().unwrap())
+        .generate()
+        .await
+        .unwrap();
+
+    let report = BenchmarkConfig::new(vec![target], &data, Duration::from_millis(500))
+        .concurrency(2)
+        .out_file(&results)
+        .run()
+        .await
+        .unwrap();
+    let written = std::fs::read_to_string(&results).unwrap();
+    assert!(written.starts_with("total_ns,start_ns,"));
+    assert_eq!(written.lines().count(), report.responses.len() + 1);
+    assert!(manifest.exists());
+    std::fs::remove_file(&results).unwrap();
+    std::fs::remove_file(&manifest).unwrap();
+
+    // nothing listens on a port that was just released, every refused
+    // connection counts as failed commands
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed = Target::Tcp(listener.local_addr().unwrap());
+    drop(listener);
+    let report = BenchmarkConfig::new(vec![closed], &data, Duration::from_millis(500))
+        .concurrency(1)
+        .run()
+        .await
+        .unwrap();
+    std::fs::remove_file(&data).unwrap();
+    let summary = &report.hosts[0].1;
+    assert!(summary.commands() > 0);
+    assert_eq!(summary.errors(), summary.commands());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_dropped_connections_are_counted_as_errors() {
+    // accepts every connection and closes it right away
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = Target::Tcp(listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            drop(listener.accept().await.unwrap());
+        }
+    });
+    let data = std::env::temp_dir().join(format!("dropped-{}.bin", std::process::id()));
+    GenerateConfig::new(&data)
+        .size(50)
+        .pattern("SET-GET:1,GET:1".parse().unwrap())
+        .generate()
+        .await
+        .unwrap();
+
+    for mode in [ConnectionMode::PerPattern, ConnectionMode::Persistent] {
+        let report = BenchmarkConfig::new(vec![target.clone()], &data, Duration::from_millis(500))
+            .concurrency(2)
+            .connection(mode)
+            .run()
+            .await
+            .unwrap();
+        let summary = &report.hosts[0].1;
+        assert!(!report.responses.is_empty(), "{:?}", mode);
+        assert!(summary.commands() > 0, "{:?}", mode);
+        assert_eq!(summary.errors(), summary.commands(), "{:?}", mode);
+    }
+    std::fs::remove_file(&data).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failing_auto_tune_step_misses_the_objective() {
+    let target = spawn_server().await;
+    let dir = std::env::temp_dir();
+    let data = dir.join(format!("tune-{}.bin", std::process::id()));
+    let results = dir.join(format!("tune-{}.csv", std::process::id()));
+    GenerateConfig::new(&data)
+        .size(10)
+        .generate()
+        .await
+        .unwrap();
+
+    // no process has this id, so sampling the server fails every step
+    let report = BenchmarkConfig::new(vec![target], &data, Duration::from_millis(200))
+        .concurrency(4)
+        .server_pid(u32::MAX)
+        .out_file(&results)
+        .auto_tune("p99<1s".parse().unwrap())
+        .tune()
+        .await
+        .unwrap();
+    assert_eq!(report.steps.len(), 1);
+    assert!(!report.steps[0].passed);
+    assert!(report.best().is_none());
+    let steps = results.with_extension("steps.csv");
+    assert_eq!(std::fs::read_to_string(&steps).unwrap().lines().count(), 2);
+    std::fs::remove_file(&steps).unwrap();
+    std::fs::remove_file(&data).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_hash_routing_rejects_patterns_spanning_hosts() {
+    let targets = vec![spawn_server().await, spawn_server().await];
+    let data = std::env::temp_dir().join(format!("hash-routing-{}.bin", std::process::id()));
+    let generate = GenerateConfig::new(&data)
+        .size(100)
+        .pattern("SET-GET-DEL-SET-DEL:1".parse().unwrap());
+
+    // every SET starts on a new key, which is owned by either host
+    generate.clone().generate().await.unwrap();
+    let err = BenchmarkConfig::new(targets.clone(), &data, Duration::from_millis(300))
+        .routing(Routing::Hash)
+        .run()
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("--routing hash"), "{}", err);
+
+    let report = BenchmarkConfig::new(targets, &data, Duration::from_millis(300))
+        .routing(Routing::Hash)
+        .concurrency(2)
+        .generate(generate)
+        .run()
+        .await
+        .unwrap();
+    std::fs::remove_file(&data).unwrap();
+    assert!(!report.responses.is_empty());
+    let errors: usize = report.hosts.iter().map(|(_, s)| s.errors()).sum();
+    assert_eq!(errors, 0);
+}